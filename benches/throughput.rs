@@ -0,0 +1,90 @@
+//! Measures how fast packets of various sizes are transferred over a loopback TCP connection.
+//!
+//! Run with `cargo bench --bench throughput`.
+use asnet::{EventKind, Host};
+use std::io::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The total size of packets sent in every run.
+const TOTAL_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() -> Result<(), Error> {
+    for (i, size) in [16, 64, 256, 1024, 4096, 16384].iter().enumerate() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 9000 + i as u16));
+        let count = TOTAL_SIZE / size;
+        let elapsed = run(addr, *size, count)?;
+
+        let secs = elapsed.as_secs_f64();
+        println!(
+            "{:>6} B packets: {:>10.0} packets/s {:>8.1} MiB/s",
+            size,
+            count as f64 / secs,
+            (count * size) as f64 / secs / (1024.0 * 1024.0)
+        );
+    }
+
+    Ok(())
+}
+
+/// Sends `count` packets of `size` bytes from a client to a server, returns the time it took the server to receive them.
+fn run(addr: SocketAddr, size: usize, count: usize) -> Result<Duration, Error> {
+    let mut server = Host::<()>::builder().max_packet_size(size).server(addr)?;
+
+    let client = thread::spawn(move || -> Result<(), Error> {
+        let mut client = Host::<()>::builder().max_packet_size(size).client()?;
+        let packet = vec![0u8; size];
+
+        client.connect(addr)?;
+        loop {
+            let event = match client.process(Duration::from_secs(1))? {
+                Some(event) => event,
+                None => continue,
+            };
+
+            match event.kind {
+                EventKind::Connect => {
+                    // The packet is shared between all of the sends.
+                    let packet: std::sync::Arc<[u8]> = packet.as_slice().into();
+                    for _ in 0..count {
+                        event.peer.send(packet.clone()).unwrap();
+                    }
+
+                    event.peer.disconnect_later();
+                }
+                EventKind::Disconnect(_) => return Ok(()),
+                EventKind::Receive { .. } | EventKind::Reconnecting(_) | EventKind::Reconnected => {
+                }
+            }
+        }
+    });
+
+    let mut start = None;
+    let mut received = 0;
+    while received != count {
+        let event = match server.process(Duration::from_secs(1))? {
+            Some(event) => event,
+            None => continue,
+        };
+
+        match event.kind {
+            EventKind::Connect => start = Some(Instant::now()),
+            EventKind::Receive { .. } => received += 1,
+            EventKind::Disconnect(reason) => panic!("disconnected early: {}", reason),
+            EventKind::Reconnecting(_) | EventKind::Reconnected => {}
+        }
+    }
+
+    let elapsed = start.unwrap().elapsed();
+
+    // Let the client see the connection close.
+    while let Some(event) = server.process(Duration::from_millis(100))? {
+        if let EventKind::Disconnect(_) = event.kind {
+            break;
+        }
+    }
+
+    client.join().unwrap()?;
+    Ok(elapsed)
+}
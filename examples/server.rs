@@ -18,22 +18,24 @@ fn main() -> Result<(), Error> {
 
         match kind {
             EventKind::Connect => println!("{} connected", peer.addr()),
-            EventKind::Disconnect => {
+            EventKind::Disconnect(reason) => {
                 let who = peer
                     .data()
                     .as_ref()
                     .cloned()
                     .unwrap_or_else(|| peer.addr().to_string());
 
-                println!("{} disconnected", who);
+                println!("{} disconnected: {}", who, reason);
             }
-            EventKind::Receive(data) => {
+            // Only clients reconnect.
+            EventKind::Reconnecting(_) | EventKind::Reconnected => {}
+            EventKind::Receive { packet, .. } => {
                 let who = peer
                     .data()
                     .as_ref()
                     .cloned()
                     .unwrap_or_else(|| peer.addr().to_string());
-                let name = match String::from_utf8(data) {
+                let name = match String::from_utf8(packet) {
                     Ok(name) => name,
                     Err(_) => {
                         peer.disconnect();
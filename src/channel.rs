@@ -0,0 +1,249 @@
+use super::peer::{Delivery, Priority};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// The number of bytes of framing stored without allocating.
+const INLINE_FRAMING: usize = 16;
+
+/// How the writer interleaves packets queued on different channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelPolicy {
+    /// Channels take turns, one packet at a time.
+    RoundRobin,
+    /// Packets on channels with lower ids are always sent first.
+    Priority,
+}
+
+/// A packet ready to be handed to the transport.
+///
+/// The packet itself may be shared with other peers, only the framing belongs to this one.
+pub(crate) struct Queued {
+    pub(crate) delivery: Delivery,
+    pub(crate) header: Framing,
+    pub(crate) packet: Arc<[u8]>,
+    pub(crate) trailer: Framing,
+    /// Assigned when the packet is queued, lower is older.
+    pub(crate) order: u64,
+    /// The priority and channel the packet was queued on, assigned when it's queued. `None` for control packets.
+    pub(crate) queue: Option<(Priority, u8)>,
+    /// The round-robin cursor of its priority before the packet was popped, restored if it's put back.
+    pub(crate) cursor: usize,
+}
+
+impl Queued {
+    /// Returns the header, the packet and the trailer, in the order they are sent.
+    pub(crate) fn parts(&self) -> [&[u8]; 3] {
+        [
+            self.header.as_slice(),
+            &self.packet,
+            self.trailer.as_slice(),
+        ]
+    }
+
+    /// Returns the size of the packet including the framing.
+    pub(crate) fn len(&self) -> usize {
+        self.header.as_slice().len() + self.packet.len() + self.trailer.as_slice().len()
+    }
+}
+
+/// The bytes surrounding a packet on the wire, stored inline unless the codec produces unusually many of them.
+pub(crate) enum Framing {
+    Inline(usize, [u8; INLINE_FRAMING]),
+    Heap(Box<[u8]>),
+}
+
+impl Framing {
+    pub(crate) fn new(bytes: &[u8]) -> Framing {
+        if bytes.len() > INLINE_FRAMING {
+            return Framing::Heap(bytes.into());
+        }
+
+        let mut data = [0; INLINE_FRAMING];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Framing::Inline(bytes.len(), data)
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match self {
+            Framing::Inline(len, data) => &data[..*len],
+            Framing::Heap(data) => data,
+        }
+    }
+}
+
+/// The outgoing packets of a peer, queued separately for every priority and channel.
+///
+/// Packets of a higher priority are always sent first, the `ChannelPolicy` decides between channels of the same priority.
+/// Control packets of the protocol bypass the channels and are sent before anything else.
+pub(crate) struct OutgoingQueue {
+    control: VecDeque<Queued>,
+    /// Indexed by priority.
+    levels: Vec<Level>,
+    policy: ChannelPolicy,
+    /// Packets on channels are held back until the channel count is negotiated.
+    open: bool,
+    next_order: u64,
+    /// The number of packets queued on channels.
+    packets: usize,
+    /// The size of packets queued on channels.
+    bytes: usize,
+}
+
+impl OutgoingQueue {
+    pub(crate) fn new(channels: u8, policy: ChannelPolicy) -> OutgoingQueue {
+        let levels = (0..=Priority::High as usize)
+            .map(|_| Level {
+                channels: (0..channels).map(|_| VecDeque::new()).collect(),
+                next: 0,
+            })
+            .collect();
+
+        OutgoingQueue {
+            control: VecDeque::new(),
+            levels,
+            policy,
+            open: false,
+            next_order: 0,
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, channel: u8, priority: Priority, mut queued: Queued) {
+        queued.order = self.next_order;
+        queued.queue = Some((priority, channel));
+        self.next_order += 1;
+        self.packets += 1;
+        self.bytes += queued.len();
+
+        self.levels[priority as usize].channels[channel as usize].push_back(queued);
+    }
+
+    pub(crate) fn push_control(&mut self, mut queued: Queued) {
+        queued.queue = None;
+        self.control.push_back(queued);
+    }
+
+    /// Puts back a packet which was popped but not sent, in front of the packets of its channel.
+    ///
+    /// Packets must be put back in the reverse order they were popped.
+    pub(crate) fn unpop(&mut self, queued: Queued) {
+        match queued.queue {
+            Some((priority, channel)) => {
+                self.packets += 1;
+                self.bytes += queued.len();
+
+                let level = &mut self.levels[priority as usize];
+                level.next = queued.cursor;
+                level.channels[channel as usize].push_front(queued);
+            }
+            None => self.control.push_front(queued),
+        }
+    }
+
+    /// Starts sending packets on the first `channels` channels, discarding packets queued on the rest.
+    pub(crate) fn open(&mut self, channels: u8) {
+        for level in &mut self.levels {
+            for queue in level.channels.drain(channels as usize..) {
+                self.packets -= queue.len();
+                self.bytes -= queue.iter().map(|queued| queued.len()).sum::<usize>();
+            }
+
+            level.next = 0;
+        }
+
+        self.open = true;
+    }
+
+    /// Removes the next packet to be sent, skipping channels whose first packet can't be sent right now.
+    pub(crate) fn pop(&mut self, sendable: impl Fn(&Queued) -> bool) -> Option<Queued> {
+        if self.control.front().is_some_and(&sendable) {
+            return self.control.pop_front();
+        }
+
+        if !self.open {
+            return None;
+        }
+
+        let policy = self.policy;
+        let queued = self
+            .levels
+            .iter_mut()
+            .rev()
+            .find_map(|level| level.pop(policy, &sendable))?;
+
+        self.packets -= 1;
+        self.bytes -= queued.len();
+        Some(queued)
+    }
+
+    /// Discards the packet that was queued first among all channels. Returns false if there was none.
+    pub(crate) fn drop_oldest(&mut self) -> bool {
+        let oldest = self
+            .levels
+            .iter_mut()
+            .flat_map(|level| level.channels.iter_mut())
+            .filter(|queue| !queue.is_empty())
+            .min_by_key(|queue| queue[0].order);
+
+        match oldest.and_then(|queue| queue.pop_front()) {
+            Some(queued) => {
+                self.packets -= 1;
+                self.bytes -= queued.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of packets queued on channels.
+    pub(crate) fn packets(&self) -> usize {
+        self.packets
+    }
+
+    /// Returns the total size of packets queued on channels, as they will be sent.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns true if any control packets are waiting to be sent.
+    pub(crate) fn has_control(&self) -> bool {
+        !self.control.is_empty()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.control.is_empty()
+            && self
+                .levels
+                .iter()
+                .all(|level| level.channels.iter().all(|queue| queue.is_empty()))
+    }
+}
+
+/// The queues of every channel for a single priority.
+struct Level {
+    channels: Vec<VecDeque<Queued>>,
+    /// The channel to be tried first with `ChannelPolicy::RoundRobin`.
+    next: usize,
+}
+
+impl Level {
+    fn pop(&mut self, policy: ChannelPolicy, sendable: impl Fn(&Queued) -> bool) -> Option<Queued> {
+        let start = match policy {
+            ChannelPolicy::RoundRobin => self.next,
+            ChannelPolicy::Priority => 0,
+        };
+
+        for i in 0..self.channels.len() {
+            let channel = (start + i) % self.channels.len();
+            if self.channels[channel].front().is_some_and(&sendable) {
+                let mut queued = self.channels[channel].pop_front()?;
+                queued.cursor = self.next;
+                self.next = (channel + 1) % self.channels.len();
+                return Some(queued);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+
+/// The framing used to delimit packets in the stream of bytes.
+///
+/// The default is `U32Codec`, other codecs can be used to talk to existing services which use a different framing
+/// together with `HostBuilder::raw`.
+pub trait Codec: Send + Sync {
+    /// Appends the bytes that precede a packet on the wire to `dst`.
+    ///
+    /// Returns an error if the packet can't be represented by this codec.
+    fn encode_header(&self, packet: &[u8], dst: &mut Vec<u8>) -> Result<(), Error>;
+
+    /// Appends the bytes that follow a packet on the wire to `dst`.
+    ///
+    /// Nothing is appended by default.
+    fn encode_trailer(&self, _packet: &[u8], _dst: &mut Vec<u8>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Tries to decode a single frame from the beginning of `src`.
+    ///
+    /// Returns an error if the data is malformed, which leads to disconnection of the peer.
+    fn decode(&self, src: &[u8]) -> Result<Decode, Error>;
+}
+
+/// The result of `Codec::decode`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decode {
+    /// A whole frame is available.
+    Frame {
+        /// The range of the packet within the source buffer.
+        payload: Range<usize>,
+        /// The length of the whole frame, including the header and the trailer.
+        len: usize,
+    },
+    /// More data is required to decode a frame.
+    ///
+    /// Contains the length of the packet announced so far (or a lower bound of it), if known.
+    /// Packets exceeding the maximum packet size are rejected as soon as this length is available.
+    Incomplete(Option<usize>),
+}
+
+/// Frames prefixed with their length as a big endian 32-bit integer.
+///
+/// This is the default codec. Empty packets are not allowed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct U32Codec;
+
+impl Codec for U32Codec {
+    fn encode_header(&self, packet: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+        if packet.is_empty() || packet.len() > u32::MAX as usize {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        dst.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, src: &[u8]) -> Result<Decode, Error> {
+        if src.len() < 4 {
+            return Ok(Decode::Incomplete(None));
+        }
+
+        let size = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "received an empty packet",
+            ));
+        }
+
+        Ok(prefixed(src, 4, size))
+    }
+}
+
+/// Frames prefixed with their length as a big endian 16-bit integer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct U16Codec;
+
+impl Codec for U16Codec {
+    fn encode_header(&self, packet: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+        if packet.len() > u16::MAX as usize {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        dst.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, src: &[u8]) -> Result<Decode, Error> {
+        if src.len() < 2 {
+            return Ok(Decode::Incomplete(None));
+        }
+
+        let size = u16::from_be_bytes([src[0], src[1]]) as usize;
+        Ok(prefixed(src, 2, size))
+    }
+}
+
+/// Frames prefixed with their length encoded as an unsigned LEB128 variable-length integer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VarintCodec;
+
+impl Codec for VarintCodec {
+    fn encode_header(&self, packet: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+        let mut size = packet.len() as u64;
+        loop {
+            let byte = (size & 0x7f) as u8;
+            size >>= 7;
+
+            if size == 0 {
+                dst.push(byte);
+                return Ok(());
+            }
+
+            dst.push(byte | 0x80);
+        }
+    }
+
+    fn decode(&self, src: &[u8]) -> Result<Decode, Error> {
+        let mut size = 0u64;
+        for (i, byte) in src.iter().cloned().enumerate() {
+            // A 64-bit integer takes at most 10 bytes.
+            if i == 10 || (i == 9 && byte > 1) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "packet length overflows",
+                ));
+            }
+
+            size |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                if size > usize::MAX as u64 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "packet length overflows",
+                    ));
+                }
+
+                return Ok(prefixed(src, i + 1, size as usize));
+            }
+        }
+
+        Ok(Decode::Incomplete(None))
+    }
+}
+
+/// Frames terminated with a newline character.
+///
+/// Packets must not contain the newline character themselves. A carriage return preceding the newline is
+/// not stripped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LineCodec;
+
+impl Codec for LineCodec {
+    fn encode_header(&self, packet: &[u8], _dst: &mut Vec<u8>) -> Result<(), Error> {
+        if packet.contains(&b'\n') {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        Ok(())
+    }
+
+    fn encode_trailer(&self, _packet: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+        dst.push(b'\n');
+        Ok(())
+    }
+
+    fn decode(&self, src: &[u8]) -> Result<Decode, Error> {
+        match src.iter().position(|b| *b == b'\n') {
+            Some(end) => Ok(Decode::Frame {
+                payload: 0..end,
+                len: end + 1,
+            }),
+            None => Ok(Decode::Incomplete(Some(src.len()))),
+        }
+    }
+}
+
+/// Decodes a frame consisting of a header of `header` bytes followed by a packet of `size` bytes.
+fn prefixed(src: &[u8], header: usize, size: usize) -> Decode {
+    match header.checked_add(size) {
+        Some(len) if src.len() >= len => Decode::Frame {
+            payload: header..len,
+            len,
+        },
+        _ => Decode::Incomplete(Some(size)),
+    }
+}
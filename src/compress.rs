@@ -0,0 +1,115 @@
+//! Compression of packets, each algorithm is available with the cargo feature of the same name.
+use std::io::{Error, ErrorKind};
+
+/// The id of LZ4 in the header of compressed frames, and its bit in the algorithms announced in the hello.
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 0;
+/// The id of Zstandard, like `LZ4`.
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+
+/// An algorithm packets are compressed with, see `HostBuilder::compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, which is very fast but compresses less. Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at this level, which compresses better at the expense of speed. Requires the `zstd` feature.
+    ///
+    /// Levels range from 1 to 22, 3 is the usual default.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => ZSTD,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn compress(self, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::block::compress_prepend_size(packet)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(packet, level),
+        }
+    }
+}
+
+/// Returns the algorithms this host can decompress as a bit set of their ids.
+pub(crate) fn supported() -> u8 {
+    #[allow(unused_mut)]
+    let mut supported = 0;
+    #[cfg(feature = "lz4")]
+    {
+        supported |= 1 << LZ4;
+    }
+    #[cfg(feature = "zstd")]
+    {
+        supported |= 1 << ZSTD;
+    }
+
+    supported
+}
+
+/// Decompresses a packet compressed with the algorithm of this id.
+///
+/// Fails without decompressing anything if the packet would be larger than `max_size`, so that a small frame can't
+/// make the host allocate a lot of memory.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn decompress(id: u8, packet: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    match id {
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            let (size, compressed) =
+                lz4_flex::block::uncompressed_size(packet).map_err(invalid_data)?;
+            check_size(size as u64, max_size)?;
+
+            let mut decompressed = vec![0; size];
+            let n = lz4_flex::block::decompress_into(compressed, &mut decompressed)
+                .map_err(invalid_data)?;
+            decompressed.truncate(n);
+
+            Ok(decompressed)
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            // The size is written by the compressor, frames without it are only sent by other implementations.
+            let size = zstd::zstd_safe::get_frame_content_size(packet)
+                .ok()
+                .flatten()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown decompressed size"))?;
+            check_size(size, max_size)?;
+
+            // Corrupt frames are reported as `ErrorKind::Other`, which would be taken for a failure of the host.
+            zstd::bulk::decompress(packet, size as usize).map_err(invalid_data)
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "unsupported compression algorithm",
+        )),
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn check_size(size: u64, max_size: usize) -> Result<(), Error> {
+    if size > max_size as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "decompressed packet exceeds the maximum packet size",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
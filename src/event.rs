@@ -1,4 +1,6 @@
 use super::peer::Peer;
+use std::fmt::{self, Display, Formatter};
+use std::io::Error;
 
 /// An event that occured on a particular peer.
 #[derive(Debug)]
@@ -8,12 +10,108 @@ pub struct Event<'a, T> {
 }
 
 /// The type of an event.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum EventKind {
     /// Peer was disconnected.
     Connect,
     /// Peer was disconnected.
-    Disconnect,
+    Disconnect(DisconnectReason),
+    /// The connection was lost and the peer is reconnecting, see `Peer::set_reconnect_policy`.
+    Reconnecting(DisconnectReason),
+    /// The connection was established again after `Reconnecting`.
+    Reconnected,
     /// The remote sie of a peer has sent a packet.
-    Receive(Vec<u8>),
+    Receive {
+        /// The channel the packet was sent on.
+        channel: u8,
+        packet: Vec<u8>,
+    },
+}
+
+/// The reason why a peer was disconnected.
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// The peer was disconnected locally using `Peer::disconnect`.
+    Local,
+    /// The remote side closed the connection.
+    Closed,
+    /// Nothing was sent or received for longer than the timeout configured on the `HostBuilder`,
+    /// or the connection wasn't established within the connect timeout.
+    Timeout,
+    /// The remote side refused the connection.
+    Refused(Error),
+    /// The connection couldn't be established for another reason, usually because there is no route to the remote side.
+    Unreachable(Error),
+    /// The connection was reset or aborted, usually because the remote side went away without closing it.
+    Reset(Error),
+    /// The remote side sent data that doesn't conform to the protocol.
+    InvalidData(Error),
+    /// The remote side announced a packet of this size, which exceeds the maximum packet size of the peer.
+    PacketTooLarge(usize),
+    /// The outgoing queue was over the high-water mark, see `Backpressure::Disconnect`.
+    QueueFull,
+    /// The remote side sent packets faster than allowed, see `RateLimitAction::Disconnect`.
+    RateLimited,
+    /// The token presented by the client was rejected, see `HostBuilder::authenticator`.
+    AuthFailed,
+    /// The remote side announced another application id, see `HostBuilder::application_id`.
+    WrongApplication(u32),
+    /// The remote side announced this protocol version, which isn't compatible with the range supported by the host,
+    /// see `HostBuilder::protocol_version`.
+    IncompatibleVersion(u16),
+}
+
+impl PartialEq for DisconnectReason {
+    /// Two reasons are equal if they are of the same variant and their errors (if any) are of the same kind.
+    fn eq(&self, other: &DisconnectReason) -> bool {
+        match (self, other) {
+            (DisconnectReason::Local, DisconnectReason::Local)
+            | (DisconnectReason::Closed, DisconnectReason::Closed)
+            | (DisconnectReason::Timeout, DisconnectReason::Timeout)
+            | (DisconnectReason::QueueFull, DisconnectReason::QueueFull)
+            | (DisconnectReason::RateLimited, DisconnectReason::RateLimited)
+            | (DisconnectReason::AuthFailed, DisconnectReason::AuthFailed) => true,
+            (DisconnectReason::WrongApplication(a), DisconnectReason::WrongApplication(b)) => {
+                a == b
+            }
+            (
+                DisconnectReason::IncompatibleVersion(a),
+                DisconnectReason::IncompatibleVersion(b),
+            ) => a == b,
+            (DisconnectReason::Refused(a), DisconnectReason::Refused(b))
+            | (DisconnectReason::Unreachable(a), DisconnectReason::Unreachable(b))
+            | (DisconnectReason::Reset(a), DisconnectReason::Reset(b))
+            | (DisconnectReason::InvalidData(a), DisconnectReason::InvalidData(b)) => {
+                a.kind() == b.kind()
+            }
+            (DisconnectReason::PacketTooLarge(a), DisconnectReason::PacketTooLarge(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Local => write!(f, "disconnected locally"),
+            DisconnectReason::Closed => write!(f, "closed by the remote side"),
+            DisconnectReason::Timeout => write!(f, "timed out"),
+            DisconnectReason::Refused(err) => write!(f, "connection refused: {}", err),
+            DisconnectReason::Unreachable(err) => write!(f, "unreachable: {}", err),
+            DisconnectReason::Reset(err) => write!(f, "connection reset: {}", err),
+            DisconnectReason::InvalidData(err) => write!(f, "invalid data: {}", err),
+            DisconnectReason::PacketTooLarge(size) => {
+                write!(f, "packet of {} bytes is too large", size)
+            }
+            DisconnectReason::QueueFull => write!(f, "outgoing queue is full"),
+            DisconnectReason::RateLimited => write!(f, "rate limit exceeded"),
+            DisconnectReason::AuthFailed => write!(f, "authentication failed"),
+            DisconnectReason::WrongApplication(app_id) => {
+                write!(f, "remote side runs another application ({:#x})", app_id)
+            }
+            DisconnectReason::IncompatibleVersion(version) => {
+                write!(f, "incompatible protocol version {}", version)
+            }
+        }
+    }
 }
@@ -1,24 +1,61 @@
-use super::event::{Event, EventKind};
-use super::peer::Peer;
-use mio::net::{TcpListener, TcpStream};
+use super::channel::ChannelPolicy;
+use super::codec::{Codec, U32Codec};
+use super::compress::Compression;
+use super::event::{DisconnectReason, Event, EventKind};
+use super::limit::{RateLimit, RateLimitAction, TokenBucket};
+#[cfg(feature = "serde")]
+use super::message::{DecodeErrorAction, Format, MessageEvent, MessageKind};
+use super::peer::{Backpressure, Connection, Peer, PeerConfig, PeerId, ProcessError, SendOptions};
+use super::resolve::{Lookups, Resolver, SystemResolver};
+use super::tcp::{self, TcpConnection};
+#[cfg(feature = "tls")]
+use super::tls::{TlsConfig, TlsConnect, TlsSession};
+use super::udp::{self, UdpConnection};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
 use mio::{Events, Poll, PollOpt, Ready, Token};
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
 use slab::Slab;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
 use std::io::{Error, ErrorKind};
 use std::marker::PhantomData;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// The token of finished lookups, `usize::MAX` is reserved by mio.
+const RESOLVER: Token = Token(usize::MAX - 1);
+
+/// Checks the token of a client, returning the initial data of its peer if it's valid.
+type Authenticator<T> = Arc<dyn Fn(SocketAddr, &[u8]) -> Option<T> + Send + Sync>;
+
 /// The host structure representing all connections.
 pub struct Host<T> {
-    listener: Option<TcpListener>,
+    listener: Option<Listener>,
+    transport: Transport,
     poll: Poll,
     poll_events: Events,
     timeout: Duration,
+    config: PeerConfig,
     events: VecDeque<HostEvent>,
     peers: Slab<Peer<T>>,
-    remove: Option<usize>,
+    generation: u64,
+    remove: Option<PeerId>,
+    addrs: HashMap<SocketAddr, PeerId>,
+    /// Reused to receive datagrams on the socket of a UDP server.
+    datagram_buffer: Vec<u8>,
+    resolver: Arc<dyn Resolver>,
+    admission: Admission,
+    authenticator: Option<Authenticator<T>>,
+    ip_rate_limit: Option<RateLimit>,
+    /// The state of the rate limit of every IP address with peers.
+    ip_buckets: HashMap<IpAddr, TokenBucket>,
+    /// Created once the first host name is resolved.
+    lookups: Option<Lookups>,
+    #[cfg(feature = "tls")]
+    tls: TlsConfig,
 }
 
 impl<T> Host<T>
@@ -46,151 +83,359 @@ where
         Host::builder().server(addr)
     }
 
-    /// Returns a reference to a peer associated with this index, None if the index is invalid.
-    pub fn peer(&self, idx: usize) -> Option<&Peer<T>> {
-        self.peers.get(idx)
+    /// Returns a reference to a peer associated with this handle, None if the handle is invalid or stale.
+    pub fn peer(&self, id: PeerId) -> Option<&Peer<T>> {
+        self.peers.get(id.idx).filter(|peer| peer.id() == id)
     }
 
-    /// Returns a mutable reference to a peer associated with this index, None if the index is invalid.
-    pub fn peer_mut(&mut self, idx: usize) -> Option<&mut Peer<T>> {
-        self.peers.get_mut(idx)
+    /// Returns a mutable reference to a peer associated with this handle, None if the handle is invalid or stale.
+    pub fn peer_mut(&mut self, id: PeerId) -> Option<&mut Peer<T>> {
+        self.peers.get_mut(id.idx).filter(|peer| peer.id() == id)
     }
 
-    /// Returns an iterator over all connected peers and their indices.
-    pub fn peers(&self) -> impl Iterator<Item = (usize, &Peer<T>)> {
+    /// Returns an iterator over all connected peers and their handles.
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, &Peer<T>)> {
         self.peers
             .iter()
             .filter(|(_, peer)| peer.connected() && peer.acknowledged())
+            .map(|(_, peer)| (peer.id(), peer))
     }
 
-    /// Returns an iterator over all connected peers and their indices.
-    pub fn peers_mut(&mut self) -> impl Iterator<Item = (usize, &mut Peer<T>)> {
+    /// Returns an iterator over all connected peers and their handles.
+    pub fn peers_mut(&mut self) -> impl Iterator<Item = (PeerId, &mut Peer<T>)> {
         self.peers
             .iter_mut()
             .filter(|(_, peer)| peer.connected() && peer.acknowledged())
+            .map(|(_, peer)| (peer.id(), peer))
     }
 
     /// Connects to a remote asnet server.
     ///
-    /// Ifthis function succeeds, a `Connect` event will be always generated, however, if the remote side declines the connection,
-    /// a `Disconnect` even will be generated immediately after that.
-    pub fn connect<'a>(&'a mut self, addr: impl ToSocketAddrs) -> Result<&'a mut Peer<T>, Error> {
-        let addr = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| ErrorKind::NotFound)?;
-        let stream = match TcpStream::connect(&addr) {
-            Ok(stream) => Some(stream),
-            Err(err) => {
-                if err.kind() != ErrorKind::ConnectionRefused {
-                    return Err(err);
-                }
+    /// A `Connect` event is generated once the remote side accepts the connection. If it doesn't,
+    /// only a `Disconnect` event is generated, with `DisconnectReason::Timeout` if the connect timeout configured
+    /// on the `HostBuilder` elapses first.
+    ///
+    /// If `addr` resolves to multiple addresses, they are tried in turn until one of them accepts the connection,
+    /// each of them within the connect timeout. Only the outcome of the last attempt is reported.
+    ///
+    /// Packets can be sent right away, they are queued until the connection is established.
+    ///
+    /// The peer can reconnect by itself after losing its connection, see `Peer::set_reconnect_policy`.
+    ///
+    /// `addr` is resolved synchronously, use `connect_host` to resolve host names without blocking.
+    pub fn connect(&mut self, addr: impl ToSocketAddrs) -> Result<&mut Peer<T>, Error> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let first = *addrs.first().ok_or(ErrorKind::NotFound)?;
 
-                None
+        let id = Self::next_id(&self.peers, &mut self.generation);
+        let mut last_err = None;
+        let mut opened = None;
+        for (i, &addr) in addrs.iter().enumerate() {
+            match Self::open(&self.poll, self.transport, addr, id) {
+                Ok(connection) => {
+                    opened = Some((i, addr, connection));
+                    break;
+                }
+                Err(err) => last_err = Some(err),
             }
+        }
+
+        if let Some((i, addr, connection)) = opened {
+            let mut peer = Peer::new(addr, connection, id, self.config.clone());
+            peer.set_addrs(addrs, i + 1);
+            peer.present_token();
+
+            self.peers.insert(peer);
+            return Ok(&mut self.peers[id.idx]);
+        }
+
+        // Every attempt failed right away.
+        let err = last_err.unwrap();
+        if !matches!(
+            err.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+        ) {
+            return Err(err);
+        }
+
+        let next = addrs.len();
+        let mut peer = Peer::new(
+            first,
+            Connection::Idle(self.transport),
+            id,
+            self.config.clone(),
+        );
+        peer.set_addrs(addrs, next);
+        peer.close(tcp::connect_reason(err));
+
+        self.peers.insert(peer);
+        Ok(&mut self.peers[id.idx])
+    }
+
+    /// Connects to a remote asnet server, resolving its host name without blocking.
+    ///
+    /// The name is resolved on a helper thread by the `Resolver` configured on the `HostBuilder`, meanwhile
+    /// the peer is `Peer::resolving`. The resolved addresses are then tried like with `connect`,
+    /// the connect timeout of the first attempt includes the lookup.
+    ///
+    /// If the lookup fails, a `Disconnect` event with `DisconnectReason::Unreachable` is generated.
+    pub fn connect_host(&mut self, host: &str, port: u16) -> Result<&mut Peer<T>, Error> {
+        if self.lookups.is_none() {
+            self.lookups = Some(Lookups::new(&self.poll, RESOLVER)?);
+        }
+
+        let id = Self::next_id(&self.peers, &mut self.generation);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        let connection = Connection::Idle(self.transport);
+        let mut peer = Peer::new(addr, connection, id, self.config.clone());
+        peer.present_token();
+        self.peers.insert(peer);
+
+        if let Some(ref lookups) = self.lookups {
+            lookups.start(self.resolver.clone(), id, host.to_owned(), port);
+        }
+
+        Ok(&mut self.peers[id.idx])
+    }
+
+    /// Connects to a remote asnet server over TLS, verifying that its certificate is valid for `server_name`.
+    ///
+    /// Uses the client configuration set by `HostBuilder::tls_client`. The `Connect` event is generated once
+    /// the handshake finishes, which has to happen within the connect timeout. Otherwise this works like `connect`,
+    /// reconnects are encrypted as well.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if there is no client configuration, the transport isn't TCP
+    /// or `server_name` is invalid.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+    ) -> Result<&mut Peer<T>, Error> {
+        let config = match (self.transport, &self.tls.client) {
+            (Transport::Tcp, Some(config)) => config.clone(),
+            _ => return Err(ErrorKind::InvalidInput.into()),
         };
 
-        let entry = self.peers.vacant_entry();
-        let idx = entry.key();
-
-        self.events.push_back(HostEvent {
-            kind: EventKind::Connect,
-            peer: idx,
-        });
-
-        if let Some(ref stream) = stream {
-            self.poll.register(
-                stream,
-                Token(entry.key() + 1),
-                Ready::all(),
-                PollOpt::edge(),
-            )?;
-        } else {
-            self.events.push_back(HostEvent {
-                kind: EventKind::Disconnect,
-                peer: idx,
-            });
+        let connect = TlsConnect::new(config, server_name)?;
+        let id = self.connect(addr)?.id();
+        let result = self[id].start_tls(connect);
+
+        if let Err(err) = result {
+            self.peers.remove(id.idx);
+            return Err(err);
+        }
+
+        Ok(&mut self[id])
+    }
+
+    /// Starts connecting to `addr` on behalf of the peer with this handle.
+    fn open(
+        poll: &Poll,
+        transport: Transport,
+        addr: SocketAddr,
+        id: PeerId,
+    ) -> Result<Connection, Error> {
+        match transport {
+            Transport::Tcp => {
+                let stream = TcpStream::connect(&addr)?;
+                poll.register(&stream, Token(id.idx + 1), Ready::all(), PollOpt::edge())?;
+
+                Ok(Connection::Tcp(TcpConnection::connect(stream)))
+            }
+            Transport::Udp => {
+                let local = match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+
+                let socket = UdpSocket::bind(&SocketAddr::new(local, 0))?;
+                socket.connect(addr)?;
+                poll.register(
+                    &socket,
+                    Token(id.idx + 1),
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )?;
+
+                Ok(Connection::Udp(UdpConnection::connect(
+                    socket,
+                    addr,
+                    Instant::now(),
+                )))
+            }
+        }
+    }
+
+    /// Moves a peer whose connection attempt failed on to its next address.
+    ///
+    /// Fails if there is none left, with the reason of the last address which failed right away, if any.
+    fn retry(
+        poll: &Poll,
+        transport: Transport,
+        peer: &mut Peer<T>,
+        now: Instant,
+    ) -> Result<(), Option<DisconnectReason>> {
+        let mut last_err = None;
+        while let Some(addr) = peer.next_addr() {
+            match Self::open(poll, transport, addr, peer.id())
+                .and_then(|connection| peer.replace_connection(addr, connection, now))
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => last_err = Some(err),
+            }
         }
 
-        entry.insert(Peer::new(addr, stream, idx));
-        Ok(&mut self.peers[idx])
+        Err(last_err.map(tcp::connect_reason))
     }
 
     /// Broadcasts a packet to all connected peers.
     ///
-    /// Convenience method.
-    pub fn broadcast(&mut self, packet: Vec<u8>) {
+    /// Peers which are being disconnected are skipped. Convenience method.
+    pub fn broadcast(&mut self, packet: impl Into<Arc<[u8]>>) {
+        self.broadcast_with(packet, SendOptions::new());
+    }
+
+    /// Broadcasts a packet to all connected peers with the specified options.
+    ///
+    /// The packet is shared by all the peers rather than copied for each of them.
+    /// Peers which are being disconnected or can't send the packet are skipped.
+    pub fn broadcast_with(
+        &mut self,
+        packet: impl Into<Arc<[u8]>>,
+        options: impl Into<SendOptions>,
+    ) {
+        let packet = packet.into();
+        let options = options.into();
         for (_, peer) in self
             .peers
             .iter_mut()
             .filter(|(_, peer)| peer.connected() && peer.acknowledged())
         {
-            peer.send(packet.clone());
+            let _ = peer.send_with(packet.clone(), options);
         }
     }
 
+    /// Allocates a handle for the peer that will be inserted next.
+    fn next_id(peers: &Slab<Peer<T>>, generation: &mut u64) -> PeerId {
+        let id = PeerId {
+            idx: peers.vacant_key(),
+            generation: *generation,
+        };
+
+        *generation += 1;
+        id
+    }
+
     fn process_internal(&mut self, timeout: Duration) -> Result<(), Error> {
         let now = Instant::now();
         // Wake up peers and collect incoming packets.
-        for (idx, peer) in self.peers.iter_mut() {
-            if now - peer.last_activity() >= self.timeout {
-                self.events.push_back(HostEvent {
-                    kind: EventKind::Disconnect,
-                    peer: idx,
-                });
-                continue;
-            }
+        for (_, peer) in self.peers.iter_mut() {
+            let id = peer.id();
+            if let Some(at) = peer.waiting() {
+                if now >= at {
+                    peer.stop_waiting(now);
+                    if let Err(reason) = Self::retry(&self.poll, self.transport, peer, now) {
+                        peer.close(reason.unwrap_or_else(unreachable));
+                    }
+                }
+            } else if peer.connected() {
+                let reason = if peer.timed_out(now, self.timeout) {
+                    Some(DisconnectReason::Timeout)
+                } else {
+                    match peer.process(now, self.timeout) {
+                        Ok(()) => None,
+                        Err(ProcessError::Disconnect(reason)) => Some(reason),
+                        Err(ProcessError::Io(err)) => return Err(err),
+                    }
+                };
 
-            if let Err(err) = peer.process() {
-                match err.kind() {
-                    ErrorKind::InvalidData
-                    | ErrorKind::ConnectionRefused
-                    | ErrorKind::ConnectionReset
-                    | ErrorKind::ConnectionAborted
-                    | ErrorKind::BrokenPipe => {
-                        self.events.push_back(HostEvent {
-                            kind: EventKind::Disconnect,
-                            peer: idx,
-                        });
+                if let Some(reason) = reason {
+                    if let Err(last) = Self::retry(&self.poll, self.transport, peer, now) {
+                        peer.close(last.unwrap_or(reason));
                     }
-                    _ => return Err(err),
                 }
             }
 
-            for packet in peer.incoming_packets() {
-                self.events.push_back(HostEvent {
-                    kind: EventKind::Receive(packet),
-                    peer: idx,
+            if let Some(token) = peer.take_token() {
+                let data = self
+                    .authenticator
+                    .as_ref()
+                    .and_then(|authenticator| authenticator(peer.addr(), &token));
+
+                match data {
+                    Some(data) => peer.accept_token(data),
+                    None => peer.reject_token(),
+                }
+            }
+
+            if let Some(kind) = peer.take_established() {
+                self.events.push_back(HostEvent { kind, peer: id });
+            }
+
+            if peer.has_incoming_packets() {
+                let ip_buckets = &mut self.ip_buckets;
+                let mut ip_limit = self.ip_rate_limit.map(|limit| {
+                    ip_buckets
+                        .entry(peer.addr().ip())
+                        .or_insert_with(|| TokenBucket::new(limit, now))
                 });
+
+                while let Some((channel, packet)) = peer.next_packet(now, ip_limit.as_deref_mut()) {
+                    self.events.push_back(HostEvent {
+                        kind: EventKind::Receive { channel, packet },
+                        peer: id,
+                    });
+                }
+            }
+
+            if let Some(reason) = peer.take_disconnect() {
+                let kind = match peer.schedule_reconnect(&reason, now) {
+                    Some(true) => Some(EventKind::Reconnecting(reason)),
+                    // Another attempt failed.
+                    Some(false) => None,
+                    None => Some(EventKind::Disconnect(reason)),
+                };
+
+                if let Some(kind) = kind {
+                    self.events.push_back(HostEvent { kind, peer: id });
+                }
             }
         }
 
+        // Don't block if there already are events waiting to be returned or some peer has to be processed sooner.
+        let timeout = if self.events.is_empty() {
+            self.peers
+                .iter()
+                .filter_map(|(_, peer)| peer.deadline(self.timeout))
+                .min()
+                .map_or(timeout, |deadline| {
+                    timeout.min(deadline.saturating_duration_since(now))
+                })
+        } else {
+            Duration::from_millis(0)
+        };
+
+        let mut connections = false;
+        let mut datagrams = false;
+        let mut lookups = false;
+
         self.poll.poll(&mut self.poll_events, Some(timeout))?;
         for event in &self.poll_events {
             if event.token() == Token(0) {
-                let listener = self.listener.as_mut().unwrap();
-                let (stream, addr) = match listener.accept() {
-                    Ok((stream, addr)) => (stream, addr),
-                    Err(err) => {
-                        if err.kind() != ErrorKind::WouldBlock {
-                            return Err(err);
-                        }
-
-                        continue;
-                    }
-                };
-                let entry = self.peers.vacant_entry();
-                let key = entry.key();
-
-                self.poll
-                    .register(&stream, Token(key + 1), Ready::all(), PollOpt::edge())?;
+                match self.listener {
+                    Some(Listener::Tcp(_)) => connections = true,
+                    Some(Listener::Udp(_)) => datagrams = true,
+                    None => unreachable!(),
+                }
 
-                entry.insert(Peer::new(addr, Some(stream), key));
+                continue;
+            }
 
-                self.events.push_back(HostEvent {
-                    kind: EventKind::Connect,
-                    peer: key,
-                });
+            if event.token() == RESOLVER {
+                lookups = true;
                 continue;
             }
 
@@ -202,20 +447,204 @@ where
             peer.update_ready(event.readiness());
         }
 
+        if connections {
+            self.accept_connections()?;
+        }
+
+        if datagrams {
+            self.receive_datagrams()?;
+        }
+
+        if lookups {
+            self.finish_lookups();
+        }
+
         Ok(())
     }
 
+    /// Accepts all pending connections on the TCP listener.
+    fn accept_connections(&mut self) -> Result<(), Error> {
+        let listener = match self.listener {
+            Some(Listener::Tcp(ref listener)) => listener,
+            _ => return Ok(()),
+        };
+
+        loop {
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            };
+
+            // Dropping the stream closes it.
+            if !self.admission.admits(&self.peers, addr) {
+                continue;
+            }
+
+            let id = Self::next_id(&self.peers, &mut self.generation);
+            self.poll
+                .register(&stream, Token(id.idx + 1), Ready::all(), PollOpt::edge())?;
+
+            #[allow(unused_mut)]
+            let mut tcp = TcpConnection::new(Some(stream));
+            #[cfg(feature = "tls")]
+            if let Some(ref config) = self.tls.server {
+                tcp.start_tls(TlsSession::server(config.clone())?);
+            }
+
+            let peer = Peer::new(addr, Connection::Tcp(tcp), id, self.config.clone());
+            let authenticate = self.authenticator.is_some();
+            Self::insert_accepted(&mut self.peers, &mut self.events, peer, authenticate);
+        }
+
+        Ok(())
+    }
+
+    /// Starts connecting the peers whose host names were resolved.
+    fn finish_lookups(&mut self) {
+        let finished = match self.lookups {
+            Some(ref lookups) => lookups.finished(),
+            None => return,
+        };
+
+        let now = Instant::now();
+        for (id, result) in finished {
+            // The peer might have been disconnected or timed out in the meantime.
+            let peer = match self.peers.get_mut(id.idx) {
+                Some(peer) if peer.id() == id && peer.connected() && peer.resolving() => peer,
+                _ => continue,
+            };
+
+            match result {
+                Ok(addrs) => {
+                    peer.set_addrs(addrs, 0);
+                    if let Err(reason) = Self::retry(&self.poll, self.transport, peer, now) {
+                        peer.close(reason.unwrap_or_else(unreachable));
+                    }
+                }
+                Err(err) => peer.close(DisconnectReason::Unreachable(err)),
+            }
+        }
+    }
+
+    /// Receives datagrams on the socket of a UDP server and dispatches them to peers, accepting new connections.
+    fn receive_datagrams(&mut self) -> Result<(), Error> {
+        let socket = match self.listener {
+            Some(Listener::Udp(ref socket)) => socket.clone(),
+            _ => return Ok(()),
+        };
+
+        let now = Instant::now();
+        // Taken out while datagrams are handled, so that peers can be borrowed meanwhile.
+        let mut buffer = std::mem::take(&mut self.datagram_buffer);
+        buffer.resize(udp::MAX_DATAGRAM_SIZE, 0);
+
+        let result = loop {
+            let (n, addr) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            let datagram = &buffer[..n];
+            let request = udp::connect_request(datagram);
+            if let Some(id) = self.addrs.get(&addr).cloned() {
+                // A connect request of another session means the remote side has started over.
+                let stale = request.is_some_and(|session| self[id].session() != Some(session));
+                if !stale {
+                    self[id].handle_datagram(datagram, now);
+                    continue;
+                }
+
+                self[id].close(DisconnectReason::Reset(Error::new(
+                    ErrorKind::ConnectionReset,
+                    "the remote side connected again",
+                )));
+                self.addrs.remove(&addr);
+            }
+
+            let session = match request {
+                Some(session) => session,
+                None => continue,
+            };
+
+            // The remote side times out.
+            if !self.admission.admits(&self.peers, addr) {
+                continue;
+            }
+
+            // The remote side sends the request again if the acceptance can't be sent.
+            let connection = match UdpConnection::accept(socket.clone(), addr, session) {
+                Ok(udp) => Connection::Udp(udp),
+                Err(_) => continue,
+            };
+
+            let id = Self::next_id(&self.peers, &mut self.generation);
+
+            self.addrs.insert(addr, id);
+            let peer = Peer::new(addr, connection, id, self.config.clone());
+            let authenticate = self.authenticator.is_some();
+            Self::insert_accepted(&mut self.peers, &mut self.events, peer, authenticate);
+        };
+
+        self.datagram_buffer = buffer;
+        result
+    }
+
+    /// Inserts a peer accepted by the server.
+    ///
+    /// The `Connect` event is generated right away, unless the connection is encrypted or has to be authenticated first.
+    fn insert_accepted(
+        peers: &mut Slab<Peer<T>>,
+        events: &mut VecDeque<HostEvent>,
+        mut peer: Peer<T>,
+        authenticate: bool,
+    ) {
+        if authenticate {
+            peer.expect_token();
+        }
+
+        let id = peer.id();
+        if let Some(kind) = peer.take_established() {
+            events.push_back(HostEvent { kind, peer: id });
+        }
+
+        peers.insert(peer);
+    }
+
     fn pop_event(&mut self) -> Option<HostEvent> {
-        if let Some(peer) = self.remove.take() {
-            self.peers.remove(peer);
+        if let Some(id) = self.remove.take() {
+            if let Some(peer) = self.peer(id) {
+                let addr = peer.addr();
+                if self.addrs.get(&addr) == Some(&id) {
+                    self.addrs.remove(&addr);
+                }
+
+                self.peers.remove(id.idx);
+
+                // The limit of an IP address is kept as long as any of its peers is, so reconnecting doesn't reset it.
+                if !self
+                    .peers
+                    .iter()
+                    .any(|(_, peer)| peer.addr().ip() == addr.ip())
+                {
+                    self.ip_buckets.remove(&addr.ip());
+                }
+            }
         }
 
-        if let Some(event) = self.events.pop_front() {
-            if event.kind == EventKind::Disconnect {
+        while let Some(event) = self.events.pop_front() {
+            // Events queued for a peer that has already been removed are stale.
+            let peer = match self.peer_mut(event.peer) {
+                Some(peer) => peer,
+                None => continue,
+            };
+
+            peer.acknowledge();
+            if let EventKind::Disconnect(_) = event.kind {
                 self.remove = Some(event.peer);
             }
 
-            self.peers[event.peer].acknowledge();
             return Some(event);
         }
 
@@ -225,11 +654,11 @@ where
     /// Sends outgoing packets and receives incoming packets. This is the only place where actual IO happens.
     ///
     /// Will block for maximum `timeout` duration of time.
-    pub fn process<'a>(&'a mut self, timeout: Duration) -> Result<Option<Event<'a, T>>, Error> {
+    pub fn process(&mut self, timeout: Duration) -> Result<Option<Event<'_, T>>, Error> {
         if let Some(HostEvent { kind, peer }) = self.pop_event() {
             return Ok(Some(Event {
                 kind,
-                peer: &mut self.peers[peer],
+                peer: &mut self[peer],
             }));
         }
 
@@ -238,50 +667,159 @@ where
     }
 
     /// Like `process`, but will block indefinitely until an event happens.
-    pub fn process_blocking<'a>(&'a mut self) -> Result<Event<'a, T>, Error> {
+    pub fn process_blocking(&mut self) -> Result<Event<'_, T>, Error> {
         loop {
             if let Some(HostEvent { kind, peer }) = self.pop_event() {
                 return Ok(Event {
                     kind,
-                    peer: &mut self.peers[peer],
+                    peer: &mut self[peer],
                 });
             }
 
             self.process_internal(self.timeout)?;
         }
     }
+
+    /// Like `process`, but deserializes received packets as messages with the format configured on the `HostBuilder`.
+    ///
+    /// Packets that aren't messages of type `M` are handled according to the `DecodeErrorAction` configured on the
+    /// `HostBuilder`. Fails with `ErrorKind::InvalidInput` if no format is configured.
+    #[cfg(feature = "serde")]
+    pub fn process_messages<M>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<MessageEvent<'_, T, M>>, Error>
+    where
+        M: DeserializeOwned,
+    {
+        let format = self.format()?;
+        if let Some((kind, peer)) = self.pop_message(&*format) {
+            return Ok(Some(MessageEvent {
+                kind,
+                peer: &mut self[peer],
+            }));
+        }
+
+        self.process_internal(timeout)?;
+        Ok(None)
+    }
+
+    /// Like `process_messages`, but will block indefinitely until an event happens.
+    #[cfg(feature = "serde")]
+    pub fn process_messages_blocking<M>(&mut self) -> Result<MessageEvent<'_, T, M>, Error>
+    where
+        M: DeserializeOwned,
+    {
+        let format = self.format()?;
+        loop {
+            if let Some((kind, peer)) = self.pop_message(&*format) {
+                return Ok(MessageEvent {
+                    kind,
+                    peer: &mut self[peer],
+                });
+            }
+
+            self.process_internal(self.timeout)?;
+        }
+    }
+
+    /// Returns the format configured on the `HostBuilder`.
+    #[cfg(feature = "serde")]
+    fn format(&self) -> Result<Arc<dyn Format>, Error> {
+        self.config.format.clone().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "no format is configured, see `HostBuilder::format`",
+            )
+        })
+    }
+
+    #[cfg(feature = "serde")]
+    fn pop_message<M>(&mut self, format: &dyn Format) -> Option<(MessageKind<M>, PeerId)>
+    where
+        M: DeserializeOwned,
+    {
+        while let Some(HostEvent { kind, peer }) = self.pop_event() {
+            match MessageKind::decode(format, kind) {
+                Ok(kind) => return Some((kind, peer)),
+                Err((channel, error)) => match self.config.decode_error_action {
+                    DecodeErrorAction::Event => {
+                        return Some((MessageKind::DecodeError { channel, error }, peer))
+                    }
+                    DecodeErrorAction::Disconnect => {
+                        self[peer].close(DisconnectReason::InvalidData(error));
+
+                        // Packets received after the invalid one must not be delivered to a disconnected peer.
+                        self.events.retain(|event| {
+                            event.peer != peer || !matches!(event.kind, EventKind::Receive { .. })
+                        });
+                    }
+                },
+            }
+        }
+
+        None
+    }
 }
 
-impl<T> Index<usize> for Host<T> {
+impl<T> Index<PeerId> for Host<T> {
     type Output = Peer<T>;
 
-    /// Returns a reference to a peer associated with this index.
+    /// Returns a reference to a peer associated with this handle.
     ///
-    /// Panics if no such peer exists.
-    fn index(&self, idx: usize) -> &Peer<T> {
-        &self.peers[idx]
+    /// Panics if no such peer exists or the handle is stale.
+    fn index(&self, id: PeerId) -> &Peer<T> {
+        match self.peers.get(id.idx) {
+            Some(peer) if peer.id() == id => peer,
+            _ => panic!("invalid peer handle {:?}", id),
+        }
     }
 }
 
-impl<T> IndexMut<usize> for Host<T> {
-    /// Returns a mutable reference to a peer associated with this index.
+impl<T> IndexMut<PeerId> for Host<T> {
+    /// Returns a mutable reference to a peer associated with this handle.
     ///
-    /// Panics if no such peer exists.
-    fn index_mut(&mut self, idx: usize) -> &mut Peer<T> {
-        &mut self.peers[idx]
+    /// Panics if no such peer exists or the handle is stale.
+    fn index_mut(&mut self, id: PeerId) -> &mut Peer<T> {
+        match self.peers.get_mut(id.idx) {
+            Some(peer) if peer.id() == id => peer,
+            _ => panic!("invalid peer handle {:?}", id),
+        }
     }
 }
 
+/// The transport protocol used by a `Host`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Packets are framed by a `Codec` in a TCP stream, they are always delivered reliably and in order.
+    Tcp,
+    /// Packets are sent as UDP datagrams, their delivery is chosen when sending them.
+    ///
+    /// Packets must fit into a single datagram. The `Codec` is not used.
+    Udp,
+}
+
 /// The builder for the `Host` structure.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone)]
 pub struct HostBuilder<T> {
     events_capacity: usize,
     timeout: Duration,
+    transport: Transport,
+    config: PeerConfig,
+    resolver: Arc<dyn Resolver>,
+    admission: Admission,
+    authenticator: Option<Authenticator<T>>,
+    ip_rate_limit: Option<RateLimit>,
+    #[cfg(feature = "tls")]
+    tls: TlsConfig,
     data: PhantomData<T>,
 }
 
 impl<T> HostBuilder<T> {
-    /// Sets the maximum time of inactivity (that means no packets sent and received) after which the peer will be disconnected.
+    /// Sets the maximum time the remote side may leave pings unanswered, after which the peer will be disconnected.
+    ///
+    /// Raw hosts can't send pings, they disconnect peers after this time of inactivity (that means no packets sent
+    /// and received) instead.
     ///
     /// The default is 5 seconds.
     pub fn timeout(mut self, timeout: Duration) -> HostBuilder<T> {
@@ -289,6 +827,302 @@ impl<T> HostBuilder<T> {
         self
     }
 
+    /// Sets how long the remote side may stay quiet before a ping is sent to it, see `Peer::rtt`.
+    ///
+    /// A remote side which keeps sending is only pinged in time to answer before the timeout, see `HostBuilder::timeout`.
+    /// Pings are answered automatically and aren't visible as packets. The default is 1 second.
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Duration) -> HostBuilder<T> {
+        self.config.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// Sets the maximum time the remote side has to accept a connection made by `Host::connect`.
+    ///
+    /// The default is 5 seconds.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> HostBuilder<T> {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets the maximum time `Peer::disconnect_later` waits for the outgoing packets to be sent before closing the connection.
+    ///
+    /// The default is 5 seconds.
+    pub fn linger(mut self, linger: Duration) -> HostBuilder<T> {
+        self.config.linger = linger;
+        self
+    }
+
+    /// Sets the maximum size of a packet that can be sent or received.
+    ///
+    /// Peers announcing a larger packet are disconnected with `DisconnectReason::PacketTooLarge` before any of it is buffered.
+    /// The limit can be overriden for each peer using `Peer::set_max_packet_size`.
+    ///
+    /// The default is 16 MiB.
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> HostBuilder<T> {
+        self.config.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets the number of bytes read from a TCP stream at once.
+    ///
+    /// Every peer keeps a read buffer of at least this size. Larger reads make bulk transfers cheaper,
+    /// packets announced by the codec get a buffer of their size regardless.
+    ///
+    /// The default is 16 KiB. Panics if `read_size` is 0.
+    pub fn read_size(mut self, read_size: usize) -> HostBuilder<T> {
+        assert!(read_size != 0, "invalid read size 0");
+
+        self.config.read_size = read_size;
+        self
+    }
+
+    /// Sets the codec used to delimit packets on the wire.
+    ///
+    /// The default is `U32Codec`.
+    pub fn codec(mut self, codec: impl Codec + 'static) -> HostBuilder<T> {
+        self.config.codec = Arc::new(codec);
+        self
+    }
+
+    /// Sets the number of channels of every peer, the remote side may support fewer of them.
+    ///
+    /// The default is 1. Panics if `channels` is 0 or 255, which is reserved by the protocol.
+    pub fn channels(mut self, channels: u8) -> HostBuilder<T> {
+        assert!(
+            channels != 0 && channels != 255,
+            "invalid channel count {}",
+            channels
+        );
+
+        self.config.channels = channels;
+        self
+    }
+
+    /// Sets how the packets queued on different channels are interleaved.
+    ///
+    /// The default is `ChannelPolicy::RoundRobin`.
+    pub fn channel_policy(mut self, channel_policy: ChannelPolicy) -> HostBuilder<T> {
+        self.config.channel_policy = channel_policy;
+        self
+    }
+
+    /// Disables the asnet protocol on top of the codec, so that packets are framed exactly as the codec does it.
+    ///
+    /// This allows talking to services which don't use asnet. Only channel 0 is available to raw hosts.
+    ///
+    /// The default is false.
+    pub fn raw(mut self, raw: bool) -> HostBuilder<T> {
+        self.config.raw = raw;
+        self
+    }
+
+    /// Sets the maximum number of bytes waiting in the outgoing queue of every peer, see `Peer::queued_bytes`.
+    ///
+    /// The default is no limit.
+    pub fn high_water_mark(mut self, high_water_mark: usize) -> HostBuilder<T> {
+        self.config.high_water_mark = Some(high_water_mark);
+        self
+    }
+
+    /// Sets what happens to packets that would fill the outgoing queue over the high-water mark.
+    ///
+    /// The default is `Backpressure::Reject`.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> HostBuilder<T> {
+        self.config.backpressure = backpressure;
+        self
+    }
+
+    /// Sets the resolver of host names passed to `Host::connect_host`.
+    ///
+    /// The default is `SystemResolver`.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> HostBuilder<T> {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets the maximum number of peers of a server, further connections are rejected.
+    ///
+    /// Peers count until their `Disconnect` event is processed. The default is no limit.
+    pub fn max_peers(mut self, max_peers: usize) -> HostBuilder<T> {
+        self.admission.max_peers = Some(max_peers);
+        self
+    }
+
+    /// Sets the maximum number of peers of a server with the same IP address, further connections from it are
+    /// rejected.
+    ///
+    /// The default is no limit.
+    pub fn max_peers_per_ip(mut self, max_peers_per_ip: usize) -> HostBuilder<T> {
+        self.admission.max_peers_per_ip = Some(max_peers_per_ip);
+        self
+    }
+
+    /// Sets a filter which decides whether a server accepts a connection from an address, for example against a ban
+    /// list.
+    ///
+    /// Rejected connections are closed before they become peers, no events are generated for them. The filter is only
+    /// called for connections within the limits. The default is to accept every connection.
+    pub fn accept_filter(
+        mut self,
+        filter: impl Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    ) -> HostBuilder<T> {
+        self.admission.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets the id of the application, which has to be the same on both sides.
+    ///
+    /// Peers announcing another id are disconnected with `DisconnectReason::WrongApplication` before the `Connect` event.
+    /// This keeps unrelated asnet applications, which happen to reach the host, out. The default is 0.
+    pub fn application_id(mut self, app_id: u32) -> HostBuilder<T> {
+        self.config.app_id = app_id;
+        self
+    }
+
+    /// Sets the latest version of the application protocol supported by the host, see `Peer::protocol_version`.
+    ///
+    /// Both sides agree on the lower of their versions. If it's below the minimum version of either side, the peer is
+    /// disconnected with `DisconnectReason::IncompatibleVersion` before the `Connect` event. The default is 0.
+    pub fn protocol_version(mut self, protocol_version: u16) -> HostBuilder<T> {
+        self.config.protocol_version = protocol_version;
+        self
+    }
+
+    /// Sets the oldest version of the application protocol supported by the host, see `HostBuilder::protocol_version`.
+    ///
+    /// The default is 0.
+    pub fn min_protocol_version(mut self, min_protocol_version: u16) -> HostBuilder<T> {
+        self.config.min_protocol_version = min_protocol_version;
+        self
+    }
+
+    /// Sets the optional features of the application protocol supported by the host, as flags.
+    ///
+    /// The features supported by both sides are available as `Peer::features` once the peer is connected.
+    /// The default is none.
+    pub fn features(mut self, features: u32) -> HostBuilder<T> {
+        self.config.features = features;
+        self
+    }
+
+    /// Compresses packets sent to peers whose hosts can decompress this algorithm, which requires its cargo feature.
+    ///
+    /// Compression is transparent to the application, received packets are decompressed before they're returned.
+    /// Only packets above the compression threshold which actually get smaller are sent compressed, packets queued
+    /// before the connection is established never are. Compressed packets decompressing to more than the maximum
+    /// packet size disconnect the peer with `DisconnectReason::InvalidData`.
+    ///
+    /// The default is no compression.
+    pub fn compression(mut self, compression: Compression) -> HostBuilder<T> {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    /// Sets the size from which packets are compressed, see `HostBuilder::compression`.
+    ///
+    /// The default is 512 bytes.
+    pub fn compression_threshold(mut self, compression_threshold: usize) -> HostBuilder<T> {
+        self.config.compression_threshold = compression_threshold;
+        self
+    }
+
+    /// Sets the format of messages sent with `Peer::send_msg` and received with `Host::process_messages`.
+    ///
+    /// The default is none, so that those fail.
+    #[cfg(feature = "serde")]
+    pub fn format(mut self, format: impl Format + 'static) -> HostBuilder<T> {
+        self.config.format = Some(Arc::new(format));
+        self
+    }
+
+    /// Sets what happens to received packets which can't be deserialized by `Host::process_messages`.
+    ///
+    /// The default is `DecodeErrorAction::Disconnect`.
+    #[cfg(feature = "serde")]
+    pub fn decode_error_action(mut self, decode_error_action: DecodeErrorAction) -> HostBuilder<T> {
+        self.config.decode_error_action = decode_error_action;
+        self
+    }
+
+    /// Sets the token presented to servers by peers connected with `Host::connect`, see `HostBuilder::authenticator`.
+    ///
+    /// The `Connect` event is generated once the server accepts the token. If it's rejected, only a `Disconnect` event
+    /// with `DisconnectReason::AuthFailed` is generated. Servers without an authenticator accept any token.
+    /// Raw hosts can't present tokens. The default is none.
+    pub fn auth_token(mut self, auth_token: impl Into<Vec<u8>>) -> HostBuilder<T> {
+        self.config.auth_token = Some(auth_token.into().into());
+        self
+    }
+
+    /// Makes a server check the token of every client, see `HostBuilder::auth_token`.
+    ///
+    /// The authenticator is called with the address of the client and its token. If it returns the data of the peer,
+    /// the `Connect` event is generated and packets can be exchanged. Otherwise the client is told so and the peer is
+    /// disconnected with `DisconnectReason::AuthFailed`, which is the only event generated for it. Clients which don't
+    /// present a token within the connect timeout are disconnected with `DisconnectReason::Timeout`.
+    ///
+    /// The default is to accept every client without a token.
+    pub fn authenticator(
+        mut self,
+        authenticator: impl Fn(SocketAddr, &[u8]) -> Option<T> + Send + Sync + 'static,
+    ) -> HostBuilder<T> {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Limits the rate of packets received from every peer, see `Peer::set_rate_limit`.
+    ///
+    /// Control messages of the protocol don't count. The default is no limit.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> HostBuilder<T> {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Limits the rate of packets received from all peers with the same IP address together.
+    ///
+    /// Packets have to be within both this limit and the limit of their peer. The default is no limit.
+    pub fn rate_limit_per_ip(mut self, rate_limit: RateLimit) -> HostBuilder<T> {
+        self.ip_rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Sets what happens to packets received over a rate limit.
+    ///
+    /// The default is `RateLimitAction::Drop`.
+    pub fn rate_limit_action(mut self, rate_limit_action: RateLimitAction) -> HostBuilder<T> {
+        self.config.rate_limit_action = rate_limit_action;
+        self
+    }
+
+    /// Encrypts the connections accepted by a server with TLS, using this certificate and key configuration.
+    ///
+    /// The `Connect` event of a peer is generated once the handshake finishes, which has to happen within
+    /// the connect timeout. If it fails, only a `Disconnect` event is generated. Only `Transport::Tcp` supports TLS.
+    ///
+    /// The default is no encryption.
+    #[cfg(feature = "tls")]
+    pub fn tls_server(mut self, config: Arc<rustls::ServerConfig>) -> HostBuilder<T> {
+        self.tls.server = Some(config);
+        self
+    }
+
+    /// Sets the configuration used by `Host::connect_tls` to verify servers.
+    ///
+    /// The default is none, so that `Host::connect_tls` fails.
+    #[cfg(feature = "tls")]
+    pub fn tls_client(mut self, config: Arc<rustls::ClientConfig>) -> HostBuilder<T> {
+        self.tls.client = Some(config);
+        self
+    }
+
+    /// Sets the transport protocol.
+    ///
+    /// The default is `Transport::Tcp`.
+    pub fn transport(mut self, transport: Transport) -> HostBuilder<T> {
+        self.transport = transport;
+        self
+    }
+
     /// Sets capacity for mio events.
     ///
     /// The default is 256.
@@ -298,32 +1132,90 @@ impl<T> HostBuilder<T> {
     }
 
     /// Creates a client host.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the minimum protocol version is higher than the protocol version.
     pub fn client(self) -> Result<Host<T>, Error> {
+        if self.config.min_protocol_version > self.config.protocol_version {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
         Ok(Host {
             listener: None,
+            transport: self.transport,
             poll: Poll::new()?,
             poll_events: Events::with_capacity(self.events_capacity),
             timeout: self.timeout,
+            config: self.config,
             events: VecDeque::new(),
             peers: Slab::new(),
+            generation: 0,
             remove: None,
+            addrs: HashMap::new(),
+            datagram_buffer: Vec::new(),
+            resolver: self.resolver,
+            admission: self.admission,
+            authenticator: self.authenticator,
+            ip_rate_limit: self.ip_rate_limit,
+            ip_buckets: HashMap::new(),
+            lookups: None,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
         })
     }
 
     /// Creates a server host.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the minimum protocol version is higher than the protocol version,
+    /// an authenticator is set for a raw host or a TLS configuration is set for another transport than TCP.
     pub fn server(self, addr: SocketAddr) -> Result<Host<T>, Error> {
-        let listener = TcpListener::bind(&addr)?;
+        if self.config.min_protocol_version > self.config.protocol_version
+            || (self.authenticator.is_some() && self.config.raw)
+        {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        #[cfg(feature = "tls")]
+        if self.tls.server.is_some() && self.transport != Transport::Tcp {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
         let poll = Poll::new()?;
-        poll.register(&listener, Token(0), Ready::all(), PollOpt::edge())?;
+        let listener = match self.transport {
+            Transport::Tcp => {
+                let listener = TcpListener::bind(&addr)?;
+                poll.register(&listener, Token(0), Ready::all(), PollOpt::edge())?;
+
+                Listener::Tcp(listener)
+            }
+            Transport::Udp => {
+                let socket = UdpSocket::bind(&addr)?;
+                poll.register(&socket, Token(0), Ready::readable(), PollOpt::edge())?;
+
+                Listener::Udp(Arc::new(socket))
+            }
+        };
 
         Ok(Host {
             listener: Some(listener),
+            transport: self.transport,
             poll,
             poll_events: Events::with_capacity(self.events_capacity),
             timeout: self.timeout,
+            config: self.config,
             events: VecDeque::new(),
             peers: Slab::new(),
+            generation: 0,
             remove: None,
+            addrs: HashMap::new(),
+            datagram_buffer: Vec::new(),
+            resolver: self.resolver,
+            admission: self.admission,
+            authenticator: self.authenticator,
+            ip_rate_limit: self.ip_rate_limit,
+            ip_buckets: HashMap::new(),
+            lookups: None,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
         })
     }
 }
@@ -333,12 +1225,125 @@ impl<T> Default for HostBuilder<T> {
         HostBuilder {
             events_capacity: 256,
             timeout: Duration::from_secs(5),
+            transport: Transport::Tcp,
+            config: PeerConfig {
+                linger: Duration::from_secs(5),
+                connect_timeout: Duration::from_secs(5),
+                heartbeat_interval: Duration::from_secs(1),
+                max_packet_size: 16 * 1024 * 1024,
+                read_size: 16 * 1024,
+                codec: Arc::new(U32Codec),
+                channels: 1,
+                channel_policy: ChannelPolicy::RoundRobin,
+                raw: false,
+                high_water_mark: None,
+                backpressure: Backpressure::Reject,
+                app_id: 0,
+                protocol_version: 0,
+                min_protocol_version: 0,
+                features: 0,
+                compression: None,
+                compression_threshold: 512,
+                auth_token: None,
+                rate_limit: None,
+                rate_limit_action: RateLimitAction::Drop,
+                #[cfg(feature = "serde")]
+                format: None,
+                #[cfg(feature = "serde")]
+                decode_error_action: DecodeErrorAction::Disconnect,
+            },
+            resolver: Arc::new(SystemResolver),
+            admission: Admission {
+                max_peers: None,
+                max_peers_per_ip: None,
+                filter: None,
+            },
+            authenticator: None,
+            ip_rate_limit: None,
+            #[cfg(feature = "tls")]
+            tls: TlsConfig::default(),
             data: PhantomData,
         }
     }
 }
 
+impl<T> Debug for HostBuilder<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("HostBuilder")
+            .field("events_capacity", &self.events_capacity)
+            .field("timeout", &self.timeout)
+            .field("transport", &self.transport)
+            .field("linger", &self.config.linger)
+            .field("connect_timeout", &self.config.connect_timeout)
+            .field("heartbeat_interval", &self.config.heartbeat_interval)
+            .field("max_packet_size", &self.config.max_packet_size)
+            .field("read_size", &self.config.read_size)
+            .field("channels", &self.config.channels)
+            .field("channel_policy", &self.config.channel_policy)
+            .field("raw", &self.config.raw)
+            .field("high_water_mark", &self.config.high_water_mark)
+            .field("backpressure", &self.config.backpressure)
+            .field("app_id", &self.config.app_id)
+            .field("protocol_version", &self.config.protocol_version)
+            .field("min_protocol_version", &self.config.min_protocol_version)
+            .field("features", &self.config.features)
+            .field("compression", &self.config.compression)
+            .field("compression_threshold", &self.config.compression_threshold)
+            .field("max_peers", &self.admission.max_peers)
+            .field("max_peers_per_ip", &self.admission.max_peers_per_ip)
+            .field("rate_limit", &self.config.rate_limit)
+            .field("rate_limit_per_ip", &self.ip_rate_limit)
+            .field("rate_limit_action", &self.config.rate_limit_action)
+            .finish()
+    }
+}
+
+/// The reason of disconnection if the remote side has no address left to try.
+fn unreachable() -> DisconnectReason {
+    DisconnectReason::Unreachable(Error::new(
+        ErrorKind::NotFound,
+        "no address of the host could be connected to",
+    ))
+}
+
+/// Decides which incoming connections become peers.
+#[derive(Clone)]
+struct Admission {
+    max_peers: Option<usize>,
+    max_peers_per_ip: Option<usize>,
+    filter: Option<Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>>,
+}
+
+impl Admission {
+    fn admits<T>(&self, peers: &Slab<Peer<T>>, addr: SocketAddr) -> bool {
+        if self
+            .max_peers
+            .is_some_and(|max_peers| peers.len() >= max_peers)
+        {
+            return false;
+        }
+
+        if let Some(max_peers_per_ip) = self.max_peers_per_ip {
+            let count = peers
+                .iter()
+                .filter(|(_, peer)| peer.addr().ip() == addr.ip())
+                .count();
+
+            if count >= max_peers_per_ip {
+                return false;
+            }
+        }
+
+        self.filter.as_ref().is_none_or(|filter| filter(addr))
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Udp(Arc<UdpSocket>),
+}
+
 struct HostEvent {
     kind: EventKind,
-    peer: usize,
+    peer: PeerId,
 }
@@ -1,10 +1,40 @@
-//! asnet is a simple asynchronous, packet-oriented networking library built on TCP.
+//! asnet is a simple asynchronous, packet-oriented networking library built on TCP or UDP.
+mod channel;
+mod codec;
+mod compress;
 mod event;
 mod host;
+mod limit;
+#[cfg(feature = "serde")]
+mod message;
 mod peer;
+mod reconnect;
+mod resolve;
+mod tcp;
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
 
-pub use event::{Event, EventKind};
-pub use host::{Host, HostBuilder};
-pub use peer::Peer;
+pub use channel::ChannelPolicy;
+pub use codec::{Codec, Decode, LineCodec, U16Codec, U32Codec, VarintCodec};
+pub use compress::Compression;
+#[cfg(feature = "serde")]
+pub use erased_serde;
+pub use event::{DisconnectReason, Event, EventKind};
+pub use host::{Host, HostBuilder, Transport};
+pub use limit::{RateLimit, RateLimitAction};
+#[cfg(feature = "bincode")]
+pub use message::Bincode;
+#[cfg(feature = "json")]
+pub use message::Json;
+#[cfg(feature = "msgpack")]
+pub use message::MessagePack;
+#[cfg(feature = "serde")]
+pub use message::{DecodeErrorAction, Format, MessageEvent, MessageKind};
+pub use peer::{Backpressure, Delivery, Peer, PeerId, Priority, SendError, SendOptions};
+pub use reconnect::ReconnectPolicy;
+pub use resolve::{Resolver, SystemResolver};
+#[cfg(feature = "tls")]
+pub use rustls;
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+/// Limits of the rate at which packets are received, enforced with token buckets.
+///
+/// Each limit allows bursts of up to the burst duration worth of packets or bytes, after which packets are let through
+/// only as fast as the limit refills. See `HostBuilder::rate_limit` and `Peer::set_rate_limit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    packets: Option<u32>,
+    bytes: Option<u64>,
+    burst: Duration,
+}
+
+impl RateLimit {
+    /// Creates a rate limit which doesn't limit anything until the packet or byte rate is set.
+    pub fn new() -> RateLimit {
+        RateLimit::default()
+    }
+
+    /// Sets the maximum number of packets received per second.
+    ///
+    /// The default is no limit.
+    pub fn packets(mut self, packets: u32) -> RateLimit {
+        self.packets = Some(packets);
+        self
+    }
+
+    /// Sets the maximum number of bytes of packets received per second.
+    ///
+    /// A packet larger than the burst allows is let through once the bucket is full. The default is no limit.
+    pub fn bytes(mut self, bytes: u64) -> RateLimit {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Sets for how long the remote side may send at its full speed before the limits apply.
+    ///
+    /// The default is 1 second.
+    pub fn burst(mut self, burst: Duration) -> RateLimit {
+        self.burst = burst;
+        self
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            packets: None,
+            bytes: None,
+            burst: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What happens to a received packet which exceeds a rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// The packet is discarded.
+    Drop,
+    /// The packet is held back and the connection isn't read for this long, so that the remote side is slowed down.
+    ///
+    /// Over UDP, datagrams arriving in the meantime are discarded as if they got lost. Pongs aren't received either,
+    /// so the pause should be shorter than the timeout.
+    Pause(Duration),
+    /// The peer is disconnected with `DisconnectReason::RateLimited`.
+    Disconnect,
+}
+
+/// The state of a `RateLimit`, with tokens for packets and bytes refilled as time passes.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    packets: f64,
+    /// May become negative after letting through a packet larger than the burst.
+    bytes: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            packets: capacity(limit.packets.map(u64::from), limit.burst),
+            bytes: capacity(limit.bytes, limit.burst),
+            updated: now,
+        }
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Refills the bucket and returns true if it has enough tokens for a packet of this size.
+    pub(crate) fn allows(&mut self, size: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+
+        let limit = self.limit;
+        refill(
+            &mut self.packets,
+            limit.packets.map(u64::from),
+            limit.burst,
+            elapsed,
+        );
+        refill(&mut self.bytes, limit.bytes, limit.burst, elapsed);
+
+        let capacity = capacity(limit.bytes, limit.burst);
+        self.packets >= 1.0 && (self.bytes >= size as f64 || self.bytes >= capacity)
+    }
+
+    /// Takes the tokens of a packet of this size, which must be allowed first.
+    pub(crate) fn take(&mut self, size: usize) {
+        if self.limit.packets.is_some() {
+            self.packets -= 1.0;
+        }
+
+        if self.limit.bytes.is_some() {
+            self.bytes -= size as f64;
+        }
+    }
+}
+
+/// Returns the number of tokens of a full bucket, infinite if there is no limit.
+fn capacity(rate: Option<u64>, burst: Duration) -> f64 {
+    match rate {
+        // Allow at least a single packet, otherwise a short burst would block everything.
+        Some(rate) => (rate as f64 * burst.as_secs_f64()).max(1.0),
+        None => f64::INFINITY,
+    }
+}
+
+fn refill(tokens: &mut f64, rate: Option<u64>, burst: Duration, elapsed: f64) {
+    if let Some(rate) = rate {
+        *tokens = (*tokens + rate as f64 * elapsed).min(capacity(Some(rate), burst));
+    }
+}
@@ -0,0 +1,244 @@
+//! Typed messages on top of packets, serialized with serde.
+use super::event::{DisconnectReason, EventKind};
+use super::peer::Peer;
+use serde::de::DeserializeOwned;
+use std::io::{Error, ErrorKind};
+
+/// A format messages are serialized with, see `HostBuilder::format`.
+///
+/// Messages are passed as erased serde types, so that a host can use any format without knowing the messages.
+/// Implementing a format for a serde crate only takes handing its serializer and deserializer to `erased_serde`.
+pub trait Format: Send + Sync {
+    /// Serializes a message into a packet.
+    fn serialize(&self, message: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error>;
+
+    /// Lets `visit` deserialize a message from a packet through the deserializer of the format.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if `visit` fails or the message doesn't take up the whole packet.
+    fn deserialize(
+        &self,
+        packet: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), Error>;
+}
+
+/// The compact binary format of bincode with its default options. Requires the `bincode` feature.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn serialize(&self, message: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        use bincode::Options;
+
+        bincode::DefaultOptions::new()
+            .serialize(message)
+            .map_err(invalid_data)
+    }
+
+    fn deserialize(
+        &self,
+        packet: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), Error> {
+        use bincode::Options;
+
+        // Reading through a cursor tells how much of the packet the message took up. The reader allocates whatever
+        // length is announced, the limit rejects lengths the packet can't hold before that.
+        let mut cursor = std::io::Cursor::new(packet);
+        let options = bincode::DefaultOptions::new().with_limit(packet.len() as u64);
+        let mut deserializer = bincode::Deserializer::with_reader(&mut cursor, options);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(invalid_data)?;
+
+        if cursor.position() != packet.len() as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "trailing bytes after the message",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// JSON, which is easy to inspect and to talk to from other languages. Requires the `json` feature.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    fn serialize(&self, message: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(message).map_err(invalid_data)
+    }
+
+    fn deserialize(
+        &self,
+        packet: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), Error> {
+        let mut deserializer = serde_json::Deserializer::from_slice(packet);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(invalid_data)?;
+        deserializer.end().map_err(invalid_data)
+    }
+}
+
+/// MessagePack with structs serialized as maps, so that fields can be added compatibly. Requires the `msgpack` feature.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MessagePack {
+    fn serialize(&self, message: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(message).map_err(invalid_data)
+    }
+
+    fn deserialize(
+        &self,
+        packet: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), Error> {
+        let mut deserializer = rmp_serde::Deserializer::new(std::io::Cursor::new(packet));
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(invalid_data)?;
+
+        if deserializer.position() != packet.len() as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "trailing bytes after the message",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// What happens to a received packet that can't be deserialized as a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeErrorAction {
+    /// A `MessageKind::DecodeError` event is generated and the peer stays connected.
+    Event,
+    /// The peer is disconnected with `DisconnectReason::InvalidData`.
+    Disconnect,
+}
+
+/// An event that occured on a particular peer, with received packets deserialized as messages of type `M`.
+///
+/// See `Host::process_messages`.
+#[derive(Debug)]
+pub struct MessageEvent<'a, T, M> {
+    pub kind: MessageKind<M>,
+    pub peer: &'a mut Peer<T>,
+}
+
+/// The type of a `MessageEvent`, like `EventKind`.
+#[derive(Debug)]
+pub enum MessageKind<M> {
+    /// Peer was connected.
+    Connect,
+    /// Peer was disconnected.
+    Disconnect(DisconnectReason),
+    /// The connection was lost and the peer is reconnecting, see `Peer::set_reconnect_policy`.
+    Reconnecting(DisconnectReason),
+    /// The connection was established again after `Reconnecting`.
+    Reconnected,
+    /// The remote side of a peer has sent a message.
+    Receive {
+        /// The channel the message was sent on.
+        channel: u8,
+        message: M,
+    },
+    /// The remote side of a peer has sent a packet that isn't a message, see `DecodeErrorAction::Event`.
+    DecodeError {
+        /// The channel the packet was sent on.
+        channel: u8,
+        error: Error,
+    },
+}
+
+impl<M> MessageKind<M>
+where
+    M: DeserializeOwned,
+{
+    /// Deserializes the packet of a `Receive` event, returning the error along with the channel if that fails.
+    pub(crate) fn decode(
+        format: &dyn Format,
+        kind: EventKind,
+    ) -> Result<MessageKind<M>, (u8, Error)> {
+        Ok(match kind {
+            EventKind::Connect => MessageKind::Connect,
+            EventKind::Disconnect(reason) => MessageKind::Disconnect(reason),
+            EventKind::Reconnecting(reason) => MessageKind::Reconnecting(reason),
+            EventKind::Reconnected => MessageKind::Reconnected,
+            EventKind::Receive { channel, packet } => MessageKind::Receive {
+                channel,
+                message: deserialize(format, &packet).map_err(|err| (channel, err))?,
+            },
+        })
+    }
+}
+
+impl<M> PartialEq for MessageKind<M>
+where
+    M: PartialEq,
+{
+    fn eq(&self, other: &MessageKind<M>) -> bool {
+        match (self, other) {
+            (MessageKind::Connect, MessageKind::Connect)
+            | (MessageKind::Reconnected, MessageKind::Reconnected) => true,
+            (MessageKind::Disconnect(a), MessageKind::Disconnect(b))
+            | (MessageKind::Reconnecting(a), MessageKind::Reconnecting(b)) => a == b,
+            (
+                MessageKind::Receive { channel, message },
+                MessageKind::Receive {
+                    channel: other_channel,
+                    message: other_message,
+                },
+            ) => channel == other_channel && message == other_message,
+            (
+                MessageKind::DecodeError { channel, error },
+                MessageKind::DecodeError {
+                    channel: other_channel,
+                    error: other_error,
+                },
+            ) => channel == other_channel && error.kind() == other_error.kind(),
+            _ => false,
+        }
+    }
+}
+
+fn deserialize<M>(format: &dyn Format, packet: &[u8]) -> Result<M, Error>
+where
+    M: DeserializeOwned,
+{
+    let mut message = None;
+    format.deserialize(packet, &mut |deserializer| {
+        message = Some(erased_serde::deserialize(deserializer)?);
+        Ok(())
+    })?;
+
+    message.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no message was deserialized"))
+}
+
+#[cfg(any(feature = "bincode", feature = "json", feature = "msgpack"))]
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
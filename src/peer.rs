@@ -1,68 +1,940 @@
-use mio::net::TcpStream;
+use super::channel::{ChannelPolicy, Framing, OutgoingQueue, Queued};
+use super::codec::Codec;
+use super::compress::{self, Compression};
+use super::event::{DisconnectReason, EventKind};
+use super::host::Transport;
+use super::limit::{RateLimit, RateLimitAction, TokenBucket};
+#[cfg(feature = "serde")]
+use super::message::{DecodeErrorAction, Format};
+use super::reconnect::ReconnectPolicy;
+use super::tcp::{self, TcpConnection};
+#[cfg(feature = "tls")]
+use super::tls::{TlsConnect, TlsSession};
+use super::udp::{self, UdpConnection};
 use mio::Ready;
+use std::cmp;
 use std::collections::VecDeque;
-use std::fmt::{self, Debug, Formatter};
-use std::io::{Error, ErrorKind, Read, Write};
+use std::error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The size of the header preceding every frame unless the host is raw, which contains the channel of the packet.
+///
+/// Over TCP, the header precedes the framing of the codec, so that the codec only ever sees the packet.
+const HEADER_SIZE: usize = 1;
+/// The header of frames carrying control messages instead of packets.
+const CONTROL: u8 = 0xff;
+/// The header of frames carrying compressed packets, which start with the id of the algorithm and the channel.
+///
+/// There can be at most 254 channels, so this is never a channel.
+const COMPRESSED: u8 = 0xfe;
+/// The size of the header of compressed frames along with the id of the algorithm and the channel.
+const COMPRESSED_HEADER_SIZE: usize = 3;
+/// A control message announcing the channel count of the sender.
+const HELLO: u8 = 0;
+/// A control message asking the remote side to answer with a pong carrying the same id.
+const PING: u8 = 1;
+/// A control message answering a ping.
+const PONG: u8 = 2;
+/// A control message carrying the token of a client.
+const AUTH: u8 = 3;
+/// A control message telling the client that its token was accepted.
+const AUTH_OK: u8 = 4;
+/// A control message telling the client that its token was rejected.
+const AUTH_FAILED: u8 = 5;
+
+/// An opaque handle identifying a peer within a `Host`.
+///
+/// Unlike slab indices, handles are never reused: once a peer is removed, its handle becomes stale and lookups
+/// with it fail instead of aliasing a newer connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    pub(crate) idx: usize,
+    pub(crate) generation: u64,
+}
 
 /// The peer structure representing a connection to a remote endpoint.
 pub struct Peer<T> {
     addr: SocketAddr,
-    stream: Option<TcpStream>,
-    ready: Ready,
+    /// All addresses of the remote side, tried in turn by every connection attempt.
+    addrs: Vec<SocketAddr>,
+    /// The index of the address to try next if connecting to `addr` fails.
+    next_addr: usize,
+    reconnect_policy: Option<ReconnectPolicy>,
+    /// Every connection attempt of a peer made with `Host::connect_tls` starts a TLS session.
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConnect>,
+    /// The number of failed reconnect attempts since the connection was lost.
+    attempts: u32,
+    /// The connection was established before, so establishing it again is a reconnect.
+    was_established: bool,
+    connection: Connection,
     data: T,
-    outgoing_packets: VecDeque<Vec<u8>>,
-    incoming_packets: VecDeque<Vec<u8>>,
-    write_state: Option<WriteState>,
-    read_state: Option<ReadState>,
+    outgoing: OutgoingQueue,
+    frames: VecDeque<Vec<u8>>,
+    incoming_packets: VecDeque<(u8, Vec<u8>)>,
+    channels: u8,
+    negotiated: bool,
+    /// The protocol version agreed on in the hellos.
+    version: Option<u16>,
+    features: u32,
+    /// The algorithm packets for the remote side are compressed with, if it can decompress them.
+    compression: Option<Compression>,
+    auth: Auth,
+    /// The reason of disconnection once a peer closing because of the remote side is flushed.
+    rejection: Option<DisconnectReason>,
+    /// Reused when encoding packets.
+    scratch: Vec<u8>,
     last_activity: Instant,
-    idx: usize,
+    /// Unless the host is raw, the remote side is considered alive as long as it answers pings.
+    last_pong: Instant,
+    /// When the remote side last sent a frame, it's only pinged once it's quiet.
+    last_received: Instant,
+    /// The id of the ping waiting for a pong and when it was sent.
+    ping: Option<(u32, Instant)>,
+    /// The id of the next ping.
+    ping_id: u32,
+    /// The smoothed round-trip time and its variance.
+    rtt: Option<(Duration, Duration)>,
+    /// The connection is given up if the remote side doesn't accept it until then.
+    connect_deadline: Instant,
+    rate_limit: Option<TokenBucket>,
+    /// Incoming packets are held back and the connection isn't read until then.
+    paused: Option<Instant>,
+    config: PeerConfig,
+    state: State,
+    id: PeerId,
     acknowledged: bool,
+    accepted: bool,
+    established: bool,
 }
 
 impl<T> Peer<T>
 where
     T: Default,
 {
-    pub(crate) fn new(addr: SocketAddr, stream: Option<TcpStream>, idx: usize) -> Peer<T> {
-        Peer {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        connection: Connection,
+        id: PeerId,
+        config: PeerConfig,
+    ) -> Peer<T> {
+        // Outgoing connections have to be accepted by the remote side first.
+        let accepted = match connection {
+            Connection::Tcp(ref tcp) => tcp.established(),
+            Connection::Udp(ref udp) => udp.established(),
+            Connection::Idle(_) => false,
+        };
+        let state = if accepted {
+            State::Connected
+        } else {
+            State::Connecting
+        };
+
+        // Raw hosts can't negotiate anything with the remote side.
+        let channels = if config.raw { 1 } else { config.channels };
+        let mut outgoing = OutgoingQueue::new(channels, config.channel_policy);
+        if config.raw {
+            outgoing.open(channels);
+        }
+
+        let now = Instant::now();
+        let mut peer = Peer {
             addr,
-            stream,
-            ready: Ready::empty(),
+            addrs: Vec::new(),
+            next_addr: 0,
+            reconnect_policy: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            attempts: 0,
+            was_established: false,
+            connection,
             data: T::default(),
-            outgoing_packets: VecDeque::new(),
+            outgoing,
+            frames: VecDeque::new(),
             incoming_packets: VecDeque::new(),
-            write_state: None,
-            read_state: None,
-            last_activity: Instant::now(),
-            idx,
+            channels,
+            negotiated: config.raw,
+            version: None,
+            features: 0,
+            compression: None,
+            auth: Auth::Done,
+            rejection: None,
+            scratch: Vec::new(),
+            last_activity: now,
+            last_pong: now,
+            last_received: now,
+            ping: None,
+            ping_id: 0,
+            rtt: None,
+            connect_deadline: now + config.connect_timeout,
+            rate_limit: config.rate_limit.map(|limit| TokenBucket::new(limit, now)),
+            paused: None,
+            config,
+            state,
+            id,
             acknowledged: false,
+            accepted,
+            established: accepted,
+        };
+
+        if !peer.negotiated {
+            let config = &peer.config;
+            let mut hello = vec![HELLO, channels];
+            hello.extend_from_slice(&config.app_id.to_be_bytes());
+            hello.extend_from_slice(&config.protocol_version.to_be_bytes());
+            hello.extend_from_slice(&config.min_protocol_version.to_be_bytes());
+            hello.extend_from_slice(&config.features.to_be_bytes());
+            hello.push(compress::supported());
+
+            match peer.encode(&[CONTROL], hello.into(), Delivery::Reliable) {
+                Ok(hello) => peer.outgoing.push_control(hello),
+                Err(_) => peer.close(DisconnectReason::InvalidData(Error::new(
+                    ErrorKind::InvalidInput,
+                    "hello can't be encoded by the codec",
+                ))),
+            }
         }
+
+        peer
     }
 
     pub(crate) fn connected(&self) -> bool {
-        self.stream.is_some()
+        matches!(
+            self.state,
+            State::Connecting | State::Connected | State::Closing(_) | State::Waiting(_)
+        )
+    }
+
+    /// Closes the connection, a `Disconnect` event with this reason will be generated for the peer.
+    ///
+    /// Does nothing if the peer is already being disconnected.
+    pub(crate) fn close(&mut self, reason: DisconnectReason) {
+        if self.connected() {
+            match self.connection {
+                Connection::Tcp(ref mut tcp) => tcp.close(),
+                Connection::Udp(ref mut udp) => udp.close(),
+                Connection::Idle(_) => {}
+            }
+
+            self.state = State::Disconnecting(reason);
+        }
+    }
+
+    /// Returns the event to be generated once after the remote side has accepted the connection, the hellos were
+    /// exchanged and the peer is authenticated, `Reconnected` if it was established before.
+    pub(crate) fn take_established(&mut self) -> Option<EventKind> {
+        if !self.open() || !std::mem::replace(&mut self.established, false) {
+            return None;
+        }
+
+        self.attempts = 0;
+        if std::mem::replace(&mut self.was_established, true) {
+            Some(EventKind::Reconnected)
+        } else {
+            Some(EventKind::Connect)
+        }
+    }
+
+    /// Prepares another connection attempt after the connection was closed for `reason`, if the reconnect policy allows it.
+    ///
+    /// Returns None if the peer should be disconnected instead, otherwise whether an established connection was lost,
+    /// as opposed to another failed attempt.
+    pub(crate) fn schedule_reconnect(
+        &mut self,
+        reason: &DisconnectReason,
+        now: Instant,
+    ) -> Option<bool> {
+        let policy = self.reconnect_policy?;
+        if self.addrs.is_empty()
+            || matches!(
+                reason,
+                DisconnectReason::Local
+                    | DisconnectReason::QueueFull
+                    | DisconnectReason::RateLimited
+                    | DisconnectReason::AuthFailed
+                    | DisconnectReason::WrongApplication(_)
+                    | DisconnectReason::IncompatibleVersion(_)
+            )
+        {
+            return None;
+        }
+
+        let lost = self.accepted && self.open();
+        if !lost {
+            self.attempts += 1;
+        }
+
+        if !policy.allows(self.attempts) {
+            return None;
+        }
+
+        let at = now + policy.delay(self.attempts);
+        self.restart();
+        self.state = State::Waiting(at);
+
+        Some(lost)
+    }
+
+    /// Starts over as if the peer was just created, keeping its data, its handle and its addresses.
+    ///
+    /// Packets which weren't sent over the lost connection are discarded.
+    fn restart(&mut self) {
+        let transport = match self.connection {
+            Connection::Tcp(_) => Transport::Tcp,
+            Connection::Udp(_) => Transport::Udp,
+            Connection::Idle(transport) => transport,
+        };
+
+        let mut peer = Peer::new(
+            self.addr,
+            Connection::Idle(transport),
+            self.id,
+            self.config.clone(),
+        );
+
+        std::mem::swap(&mut peer.data, &mut self.data);
+        peer.addrs = std::mem::take(&mut self.addrs);
+        peer.reconnect_policy = self.reconnect_policy;
+        peer.present_token();
+        #[cfg(feature = "tls")]
+        {
+            peer.tls = self.tls.take();
+        }
+        peer.rate_limit = self.rate_limit.take();
+        peer.attempts = self.attempts;
+        peer.was_established = self.was_established;
+        peer.acknowledged = self.acknowledged;
+
+        *self = peer;
+    }
+
+    /// Returns the time of the next reconnect attempt if the peer is waiting for it.
+    pub(crate) fn waiting(&self) -> Option<Instant> {
+        match self.state {
+            State::Waiting(at) => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Stops waiting, the addresses are then tried in turn again.
+    pub(crate) fn stop_waiting(&mut self, now: Instant) {
+        self.state = State::Connecting;
+        self.next_addr = 0;
+        self.connect_deadline = now + self.config.connect_timeout;
+    }
+
+    /// Returns the reason of disconnection if a `Disconnect` event should be generated for the peer.
+    pub(crate) fn take_disconnect(&mut self) -> Option<DisconnectReason> {
+        match std::mem::replace(&mut self.state, State::Disconnected) {
+            State::Disconnecting(reason) => Some(reason),
+            state => {
+                self.state = state;
+                None
+            }
+        }
     }
 
     pub(crate) fn update_ready(&mut self, ready: Ready) {
-        self.ready.insert(ready);
+        match self.connection {
+            Connection::Tcp(ref mut tcp) => tcp.update_ready(ready),
+            Connection::Udp(ref mut udp) => udp.update_ready(ready),
+            Connection::Idle(_) => {}
+        }
     }
 
-    pub(crate) fn process(&mut self) -> Result<(), Error> {
-        if self.ready.is_writable() {
-            self.process_writable()?;
+    /// Handles a datagram received on the socket of a UDP server.
+    ///
+    /// The socket is shared by all peers, so failing to answer the remote side only disconnects this peer.
+    pub(crate) fn handle_datagram(&mut self, datagram: &[u8], now: Instant) {
+        if !self.connected() {
+            return;
         }
 
-        if self.ready.is_readable() {
-            self.process_readable()?;
+        let max_frame_size = self.max_frame_size();
+        let udp = match self.connection {
+            Connection::Udp(ref mut udp) => udp,
+            _ => return,
+        };
+
+        let reason = match udp.handle_datagram(datagram, now, max_frame_size) {
+            Ok(()) => return,
+            Err(ProcessError::Disconnect(reason)) => self.packet_reason(reason),
+            Err(ProcessError::Io(err)) => DisconnectReason::Unreachable(err),
+        };
+
+        self.close(reason);
+    }
+
+    /// Returns the session of a UDP connection.
+    pub(crate) fn session(&self) -> Option<u32> {
+        match self.connection {
+            Connection::Udp(ref udp) => Some(udp.session()),
+            _ => None,
+        }
+    }
+
+    /// Processes the connection, `timeout` is the time the remote side may leave pings unanswered.
+    pub(crate) fn process(&mut self, now: Instant, timeout: Duration) -> Result<(), ProcessError> {
+        if self.paused.is_some_and(|until| now >= until) {
+            self.pause(None);
+        }
+
+        self.heartbeat(now, timeout);
+
+        let open = self.open();
+        let mut active = self.process_connection(now)?;
+
+        // Packets held back until now and answers to control messages can be sent right away.
+        if (self.open() && !open) || self.outgoing.has_control() {
+            active |= self.process_connection(now)?;
+        }
+
+        if active {
+            self.last_activity = now;
+        }
+
+        let (flushed, read_closed, established) = match self.connection {
+            Connection::Tcp(ref tcp) => (
+                self.outgoing.is_empty() && tcp.flushed(),
+                tcp.read_closed(),
+                tcp.established(),
+            ),
+            Connection::Udp(ref udp) => (
+                self.outgoing.is_empty() && udp.flushed(),
+                false,
+                udp.established(),
+            ),
+            Connection::Idle(_) => (false, false, false),
+        };
+
+        if !self.accepted && established {
+            self.accepted = true;
+            self.established = true;
+            self.last_activity = now;
+            self.last_pong = now;
+            self.last_received = now;
+
+            // The peer might already be closing if `disconnect_later` was called while connecting.
+            if let State::Connecting = self.state {
+                self.state = State::Connected;
+            }
+        }
+
+        if let State::Closing(deadline) = self.state {
+            if flushed {
+                match self.connection {
+                    Connection::Tcp(ref mut tcp) => tcp.shutdown()?,
+                    // Closing notifies the remote side.
+                    Connection::Udp(_) | Connection::Idle(_) => {}
+                }
+
+                let reason = self.closing_reason();
+                self.close(reason);
+            } else if now >= deadline {
+                let reason = self.closing_reason();
+                self.close(reason);
+            }
+        } else if read_closed && flushed {
+            self.close(DisconnectReason::Closed);
         }
 
         Ok(())
     }
 
-    pub(crate) fn last_activity(&self) -> Instant {
-        self.last_activity
+    /// Processes the transport and the frames it received. Returns whether any data was transferred.
+    fn process_connection(&mut self, now: Instant) -> Result<bool, ProcessError> {
+        let header_size = self.header_size();
+        let max_frame_size = self.max_frame_size();
+        let result = match self.connection {
+            Connection::Tcp(ref mut tcp) => tcp.process(
+                &*self.config.codec,
+                header_size,
+                max_frame_size,
+                self.config.read_size,
+                &mut self.outgoing,
+                &mut self.frames,
+            ),
+            Connection::Udp(ref mut udp) => {
+                udp.process(now, max_frame_size, &mut self.outgoing, &mut self.frames)
+            }
+            Connection::Idle(_) => Ok(false),
+        };
+
+        // Packets received before an error are still delivered.
+        self.receive_frames(now)?;
+        match result {
+            Err(ProcessError::Disconnect(reason)) => {
+                Err(ProcessError::Disconnect(self.packet_reason(reason)))
+            }
+            result => result,
+        }
+    }
+
+    /// Moves packets from the received frames to the incoming queue and handles control messages.
+    fn receive_frames(&mut self, now: Instant) -> Result<(), ProcessError> {
+        while let Some(mut frame) = self.frames.pop_front() {
+            self.last_received = now;
+
+            if self.config.raw {
+                self.incoming_packets.push_back((0, frame));
+                continue;
+            }
+
+            match frame.first().cloned() {
+                Some(CONTROL) => self.handle_control(&frame[HEADER_SIZE..], now)?,
+                Some(COMPRESSED) if frame.len() >= COMPRESSED_HEADER_SIZE => {
+                    let (id, channel) = (frame[1], frame[2]);
+                    self.check_channel(channel)?;
+
+                    let packet = compress::decompress(
+                        id,
+                        &frame[COMPRESSED_HEADER_SIZE..],
+                        self.config.max_packet_size,
+                    )?;
+                    self.incoming_packets.push_back((channel, packet));
+                }
+                Some(channel) => {
+                    self.check_channel(channel)?;
+
+                    frame.drain(..HEADER_SIZE);
+                    self.incoming_packets.push_back((channel, frame));
+                }
+                None => {
+                    return Err(
+                        Error::new(ErrorKind::InvalidData, "received an empty frame").into(),
+                    )
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails if packets can't be received on this channel.
+    fn check_channel(&self, channel: u8) -> Result<(), Error> {
+        if !self.open() || channel >= self.channels {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "received a packet on an invalid channel",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn handle_control(&mut self, message: &[u8], now: Instant) -> Result<(), ProcessError> {
+        match *message {
+            [HELLO, channels, ref hello @ ..] if !self.negotiated && channels != 0 => {
+                self.negotiate(channels, hello)?
+            }
+            [HELLO, ..] | [] => {
+                return Err(Error::new(ErrorKind::InvalidData, "received an invalid hello").into())
+            }
+            [PING, a, b, c, d] => {
+                let pong = Arc::from(&[PONG, a, b, c, d][..]);
+                if let Ok(pong) = self.encode(&[CONTROL], pong, Delivery::Unreliable) {
+                    self.outgoing.push_control(pong);
+                }
+            }
+            [PONG, a, b, c, d] => {
+                // Pongs of pings which were given up on are ignored.
+                if let Some((_, sent)) = self
+                    .ping
+                    .filter(|(id, _)| *id == u32::from_be_bytes([a, b, c, d]))
+                {
+                    self.update_rtt(now - sent);
+                    self.ping = None;
+                    self.last_pong = now;
+                }
+            }
+            [AUTH, ref token @ ..] => match self.auth {
+                Auth::Expected => self.auth = Auth::Received(token.to_vec()),
+                // Tokens are only checked if the host was configured to do so.
+                Auth::Done => self.send_control(AUTH_OK),
+                _ => {}
+            },
+            [AUTH_OK] => {
+                if let Auth::Waiting = self.auth {
+                    self.authenticate();
+                }
+            }
+            [AUTH_FAILED] => {
+                if let Auth::Waiting = self.auth {
+                    return Err(ProcessError::Disconnect(DisconnectReason::AuthFailed));
+                }
+            }
+            // Messages of newer versions of the protocol are ignored.
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Checks the hello of the remote side against the configuration of the host and agrees on the channel count,
+    /// the protocol version, the features and the compression.
+    ///
+    /// An incompatible remote side is disconnected once it has received the hello of this side, so that it can tell
+    /// why as well.
+    fn negotiate(&mut self, channels: u8, hello: &[u8]) -> Result<(), ProcessError> {
+        let (app_id, version, min_version, features, algorithms) = match *hello {
+            // The first version of the protocol only announced the channel count.
+            [] => (0, 0, 0, 0, 0),
+            [a, b, c, d, e, f, g, h, i, j, k, l, ref rest @ ..] => (
+                u32::from_be_bytes([a, b, c, d]),
+                u16::from_be_bytes([e, f]),
+                u16::from_be_bytes([g, h]),
+                u32::from_be_bytes([i, j, k, l]),
+                // Compression was announced later on.
+                rest.first().cloned().unwrap_or(0),
+            ),
+            _ => {
+                return Err(Error::new(ErrorKind::InvalidData, "received an invalid hello").into())
+            }
+        };
+
+        if app_id != self.config.app_id {
+            self.reject(DisconnectReason::WrongApplication(app_id));
+            return Ok(());
+        }
+
+        let negotiated = cmp::min(version, self.config.protocol_version);
+        if negotiated < cmp::max(min_version, self.config.min_protocol_version) {
+            self.reject(DisconnectReason::IncompatibleVersion(version));
+            return Ok(());
+        }
+
+        self.channels = cmp::min(self.channels, channels);
+        self.negotiated = true;
+        self.version = Some(negotiated);
+        self.features = features & self.config.features;
+        self.compression = self
+            .config
+            .compression
+            .filter(|compression| algorithms & (1 << compression.id()) != 0);
+        if self.open() {
+            self.outgoing.open(self.channels);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true once packets can be sent and received on channels, which requires the channel count to be
+    /// negotiated and the peer to be authenticated.
+    fn open(&self) -> bool {
+        self.negotiated && self.authenticated()
+    }
+
+    fn authenticated(&self) -> bool {
+        matches!(self.auth, Auth::Done)
+    }
+
+    /// Completes the authentication, opening the channels if they are negotiated already.
+    fn authenticate(&mut self) {
+        self.auth = Auth::Done;
+        if self.negotiated {
+            self.outgoing.open(self.channels);
+        }
+    }
+
+    /// Sends the token configured on the `HostBuilder` to the remote side, if any.
+    ///
+    /// The connection isn't established until the remote side accepts it.
+    pub(crate) fn present_token(&mut self) {
+        let token = match self.config.auth_token {
+            Some(ref token) if !self.config.raw => token.clone(),
+            _ => return,
+        };
+
+        let mut message = Vec::with_capacity(1 + token.len());
+        message.push(AUTH);
+        message.extend_from_slice(&token);
+
+        match self.encode(&[CONTROL], message.into(), Delivery::Reliable) {
+            Ok(auth) => {
+                self.outgoing.push_control(auth);
+                self.auth = Auth::Waiting;
+            }
+            Err(_) => self.close(DisconnectReason::InvalidData(Error::new(
+                ErrorKind::InvalidInput,
+                "token can't be encoded by the codec",
+            ))),
+        }
+    }
+
+    /// Makes the peer wait for the token of the remote side before it's established.
+    pub(crate) fn expect_token(&mut self) {
+        if !self.config.raw {
+            self.auth = Auth::Expected;
+        }
+    }
+
+    /// Returns the token received from the remote side, which has to be accepted or rejected.
+    pub(crate) fn take_token(&mut self) -> Option<Vec<u8>> {
+        match std::mem::replace(&mut self.auth, Auth::Expected) {
+            Auth::Received(token) => Some(token),
+            auth => {
+                self.auth = auth;
+                None
+            }
+        }
+    }
+
+    /// Accepts the token of the remote side, the peer starts with this data.
+    pub(crate) fn accept_token(&mut self, data: T) {
+        self.data = data;
+        self.send_control(AUTH_OK);
+        self.authenticate();
+    }
+
+    /// Rejects the token of the remote side, the peer is disconnected with `DisconnectReason::AuthFailed`
+    /// once the remote side is told so.
+    pub(crate) fn reject_token(&mut self) {
+        self.send_control(AUTH_FAILED);
+        self.reject(DisconnectReason::AuthFailed);
+    }
+
+    /// Closes the connection once everything queued for the remote side is sent, the peer is disconnected
+    /// with this reason.
+    fn reject(&mut self, reason: DisconnectReason) {
+        if self.connected() {
+            self.rejection = Some(reason);
+            self.state = State::Closing(Instant::now() + self.config.linger);
+        }
+    }
+
+    /// Returns the reason of disconnection once a closing peer is flushed.
+    fn closing_reason(&mut self) -> DisconnectReason {
+        self.rejection.take().unwrap_or(DisconnectReason::Local)
+    }
+
+    /// Queues a control message consisting only of its kind.
+    fn send_control(&mut self, kind: u8) {
+        if let Ok(message) = self.encode(&[CONTROL], Arc::from(&[kind][..]), Delivery::Reliable) {
+            self.outgoing.push_control(message);
+        }
+    }
+
+    /// Returns true if the liveness of the remote side is checked with pings, which requires the asnet protocol.
+    fn heartbeats(&self) -> bool {
+        !self.config.raw
+    }
+
+    /// Returns when the next ping should be sent, if any.
+    ///
+    /// The remote side is pinged once it was quiet for the heartbeat interval. While it's busy, it's only pinged in time
+    /// to answer before `timeout`, which is measured from the last pong.
+    fn next_ping(&self, timeout: Duration) -> Option<Instant> {
+        if !self.heartbeats() || !self.negotiated {
+            return None;
+        }
+
+        let interval = self.config.heartbeat_interval;
+        match (&self.state, self.ping) {
+            // A ping which wasn't answered within the interval is assumed to be lost and sent again.
+            (State::Connected, Some((_, sent))) => Some(sent + interval),
+            (State::Connected, None) => Some(
+                (self.last_received + interval)
+                    .min(self.last_pong + timeout.saturating_sub(interval)),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Sends a ping once it's due, see `next_ping`.
+    fn heartbeat(&mut self, now: Instant, timeout: Duration) {
+        if self.next_ping(timeout).is_none_or(|next| now < next) {
+            return;
+        }
+
+        let id = self.ping_id;
+        self.ping_id = self.ping_id.wrapping_add(1);
+
+        let [a, b, c, d] = id.to_be_bytes();
+        let ping = Arc::from(&[PING, a, b, c, d][..]);
+        if let Ok(ping) = self.encode(&[CONTROL], ping, Delivery::Unreliable) {
+            self.outgoing.push_control(ping);
+            self.ping = Some((id, now));
+        }
+    }
+
+    /// Updates the smoothed round-trip time with a new sample, like TCP does it.
+    fn update_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => {
+                let deviation = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, variance * 3 / 4 + deviation / 4)
+            }
+        });
+    }
+
+    /// Encodes the framing of a packet preceded by `header` for the transport of this peer.
+    fn encode(
+        &mut self,
+        header: &[u8],
+        packet: Arc<[u8]>,
+        delivery: Delivery,
+    ) -> Result<Queued, SendError> {
+        let (header, trailer) = match self.connection {
+            Connection::Tcp(_) | Connection::Idle(Transport::Tcp) => {
+                tcp::framing(&*self.config.codec, header, &packet, &mut self.scratch)?
+            }
+            Connection::Udp(_) | Connection::Idle(Transport::Udp) => {
+                if header.len() + packet.len() > udp::MAX_PAYLOAD_SIZE {
+                    return Err(SendError::TooLarge);
+                }
+
+                (Framing::new(header), Framing::new(&[]))
+            }
+        };
+
+        Ok(Queued {
+            delivery,
+            header,
+            packet,
+            trailer,
+            order: 0,
+            queue: None,
+            cursor: 0,
+        })
+    }
+
+    /// Compresses a packet for the remote side if it's above the threshold and gets smaller enough to make up for
+    /// the id of the algorithm and the channel preceding it.
+    fn compress(&self, channel: u8, packet: &[u8]) -> Option<Vec<u8>> {
+        let compression = self.compression?;
+        if packet.len() < self.config.compression_threshold {
+            return None;
+        }
+
+        let compressed = compression.compress(packet).ok()?;
+        if compressed.len() + COMPRESSED_HEADER_SIZE >= packet.len() + HEADER_SIZE {
+            return None;
+        }
+
+        let mut frame = Vec::with_capacity(COMPRESSED_HEADER_SIZE - HEADER_SIZE + compressed.len());
+        frame.extend_from_slice(&[compression.id(), channel]);
+        frame.extend_from_slice(&compressed);
+        Some(frame)
+    }
+
+    fn header_size(&self) -> usize {
+        if self.config.raw {
+            0
+        } else {
+            HEADER_SIZE
+        }
+    }
+
+    fn max_frame_size(&self) -> usize {
+        self.config
+            .max_packet_size
+            .saturating_add(self.header_size())
+    }
+
+    /// Makes the size of a frame that is too large refer to the packet it contains.
+    fn packet_reason(&self, reason: DisconnectReason) -> DisconnectReason {
+        match reason {
+            DisconnectReason::PacketTooLarge(size) if !self.config.raw => {
+                DisconnectReason::PacketTooLarge(size.saturating_sub(HEADER_SIZE))
+            }
+            reason => reason,
+        }
+    }
+
+    /// Returns the time at which the peer has to be processed again, regardless of IO readiness.
+    pub(crate) fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        let mut deadline = match self.connection {
+            Connection::Udp(ref udp) if self.connected() => udp.deadline(),
+            _ => None,
+        };
+
+        if (!self.accepted || !self.open()) && self.connected() {
+            deadline = Some(deadline.map_or(self.connect_deadline, |deadline| {
+                deadline.min(self.connect_deadline)
+            }));
+        }
+
+        if let Some(next_ping) = self.next_ping(timeout) {
+            deadline = Some(deadline.map_or(next_ping, |deadline| deadline.min(next_ping)));
+        }
+
+        if let Some(until) = self.paused {
+            deadline = Some(deadline.map_or(until, |deadline| deadline.min(until)));
+        }
+
+        match self.state {
+            State::Closing(at) | State::Waiting(at) => {
+                Some(deadline.map_or(at, |deadline| deadline.min(at)))
+            }
+            _ => deadline,
+        }
+    }
+
+    /// Sets the addresses of the remote side, `next` is the index of the one to try if the current attempt fails.
+    pub(crate) fn set_addrs(&mut self, addrs: Vec<SocketAddr>, next: usize) {
+        self.addrs = addrs;
+        self.next_addr = next;
+    }
+
+    /// Returns the next address to try if the connection attempt failed, None if there is none left
+    /// or the connection was already established.
+    pub(crate) fn next_addr(&mut self) -> Option<SocketAddr> {
+        if self.accepted {
+            return None;
+        }
+
+        let addr = self.addrs.get(self.next_addr).cloned();
+        self.next_addr += 1;
+        addr
+    }
+
+    /// Replaces a failed connection attempt with one to another address.
+    ///
+    /// Nothing is sent before the connection is established, so the outgoing queue stays intact.
+    /// Fails if a TLS session can't be started for the new connection.
+    pub(crate) fn replace_connection(
+        &mut self,
+        addr: SocketAddr,
+        #[allow(unused_mut)] mut connection: Connection,
+        now: Instant,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "tls")]
+        if let (Connection::Tcp(ref mut tcp), Some(ref connect)) = (&mut connection, &self.tls) {
+            tcp.start_tls(TlsSession::client(connect)?);
+        }
+
+        self.addr = addr;
+        self.connection = connection;
+        self.connect_deadline = now + self.config.connect_timeout;
+        Ok(())
+    }
+
+    /// Encrypts the current connection attempt and every following one with TLS.
+    #[cfg(feature = "tls")]
+    pub(crate) fn start_tls(&mut self, connect: TlsConnect) -> Result<(), Error> {
+        if let Connection::Tcp(ref mut tcp) = self.connection {
+            tcp.start_tls(TlsSession::client(&connect)?);
+        }
+
+        self.tls = Some(connect);
+        Ok(())
+    }
+
+    /// Returns true if the connection wasn't established in time, or the remote side wasn't heard from
+    /// for longer than `timeout`.
+    pub(crate) fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        if !self.accepted || !self.open() {
+            now >= self.connect_deadline
+        } else if self.heartbeats() {
+            now - self.last_pong >= timeout
+        } else {
+            now - self.last_activity >= timeout
+        }
     }
 
     pub(crate) fn acknowledged(&self) -> bool {
@@ -73,133 +945,298 @@ where
         self.acknowledged = true;
     }
 
-    fn process_writable(&mut self) -> Result<(), Error> {
-        if let Some(ref mut stream) = self.stream {
-            let mut processed = 0usize;
+    pub(crate) fn has_incoming_packets(&self) -> bool {
+        !self.incoming_packets.is_empty()
+    }
 
-            loop {
-                let write_state = match self.write_state.take() {
-                    Some(write_state) => {
-                        if write_state.done == write_state.data.len() {
-                            continue;
-                        }
+    /// Returns the next incoming packet within the rate limits of this peer and of its IP address, if any.
+    ///
+    /// Packets over the limits are handled according to the `RateLimitAction` configured on the `HostBuilder`.
+    pub(crate) fn next_packet(
+        &mut self,
+        now: Instant,
+        mut ip_limit: Option<&mut TokenBucket>,
+    ) -> Option<(u8, Vec<u8>)> {
+        while self.paused.is_none() {
+            let size = self.incoming_packets.front()?.1.len();
 
-                        write_state
-                    }
-                    None => {
-                        let mut data = match self.outgoing_packets.pop_front() {
-                            Some(data) => data,
-                            None => break,
-                        };
-
-                        for (i, b) in (data.len() as u32)
-                            .to_be_bytes()
-                            .iter()
-                            .cloned()
-                            .enumerate()
-                        {
-                            data.insert(i, b);
-                        }
-
-                        WriteState {
-                            data: data,
-                            done: 0,
-                        }
-                    }
-                };
-
-                let n = match stream.write(&write_state.data[write_state.done..]) {
-                    Ok(0) => break,
-                    Ok(n) => n,
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                        self.ready.remove(Ready::writable());
-                        break;
-                    }
-                    Err(err) => return Err(err),
-                };
+            // Both buckets are refilled before any tokens are taken.
+            let allowed = self
+                .rate_limit
+                .as_mut()
+                .is_none_or(|bucket| bucket.allows(size, now));
+            let allowed = ip_limit
+                .as_deref_mut()
+                .is_none_or(|bucket| bucket.allows(size, now))
+                && allowed;
 
-                processed += n;
+            if allowed {
+                for bucket in self.rate_limit.iter_mut().chain(ip_limit.as_deref_mut()) {
+                    bucket.take(size);
+                }
+
+                return self.incoming_packets.pop_front();
             }
 
-            if processed != 0 {
-                self.last_activity = Instant::now();
+            match self.config.rate_limit_action {
+                RateLimitAction::Drop => {
+                    self.incoming_packets.pop_front();
+                }
+                RateLimitAction::Pause(duration) => self.pause(Some(now + duration)),
+                RateLimitAction::Disconnect => {
+                    self.incoming_packets.clear();
+                    self.close(DisconnectReason::RateLimited);
+                }
             }
         }
 
-        Ok(())
+        None
     }
 
-    fn process_readable(&mut self) -> Result<(), Error> {
-        if let Some(ref mut stream) = self.stream {
-            let mut processed = 0usize;
+    /// Stops reading from the connection until the given time, or resumes it.
+    fn pause(&mut self, until: Option<Instant>) {
+        self.paused = until;
+        match self.connection {
+            Connection::Tcp(ref mut tcp) => tcp.pause_reading(until.is_some()),
+            Connection::Udp(ref mut udp) => udp.pause_reading(until.is_some()),
+            Connection::Idle(_) => {}
+        }
+    }
 
-            loop {
-                let mut buffer = [0u8; 512];
-                let n = match stream.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => n,
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                        self.ready.remove(Ready::readable());
-                        break;
-                    }
-                    Err(err) => return Err(err),
-                };
-
-                for e in buffer[0..n].iter().cloned() {
-                    self.read_state = match self.read_state.take() {
-                        Some(ReadState::Size1(a)) => Some(ReadState::Size2(a, e)),
-                        Some(ReadState::Size2(a, b)) => Some(ReadState::Size3(a, b, e)),
-                        Some(ReadState::Size3(a, b, c)) => {
-                            let size = u32::from_be_bytes([a, b, c, e]);
-                            if size == 0 {
-                                return Err(ErrorKind::InvalidData.into());
-                            }
-
-                            Some(ReadState::Packet(Vec::new(), size as usize))
-                        }
-                        Some(ReadState::Packet(mut packet, size)) => {
-                            packet.push(e);
-                            if packet.len() == size {
-                                self.incoming_packets.push_back(packet);
-                                None
-                            } else {
-                                Some(ReadState::Packet(packet, size))
-                            }
-                        }
-                        None => Some(ReadState::Size1(e)),
-                    };
-                }
+    /// Disconnects this peer.
+    ///
+    /// Packets that haven't been sent yet are discarded.
+    pub fn disconnect(&mut self) {
+        self.close(DisconnectReason::Local);
+    }
 
-                processed += n;
+    /// Disconnects this peer after all queued packets are sent.
+    ///
+    /// No new packets can be queued after calling this function. Once the outgoing queue is flushed,
+    /// the write half of the connection is shut down and a `Disconnect` event with `DisconnectReason::Local` is generated.
+    /// If the queue can't be flushed within the linger duration configured on the `HostBuilder`, the remaining packets are discarded.
+    pub fn disconnect_later(&mut self) {
+        match self.state {
+            State::Connecting | State::Connected => {
+                self.state = State::Closing(Instant::now() + self.config.linger)
             }
+            // There is no connection to flush the packets to.
+            State::Waiting(_) => self.close(DisconnectReason::Local),
+            _ => {}
+        }
+    }
+
+    /// Queues a packet to be sent reliably on channel 0 with normal priority.
+    ///
+    /// Fails if the peer is disconnected or is being disconnected, if the packet exceeds the maximum packet size
+    /// or if it can't be encoded by the codec.
+    pub fn send(&mut self, packet: impl Into<Arc<[u8]>>) -> Result<(), SendError> {
+        self.send_with(packet, SendOptions::new())
+    }
+
+    /// Queues a packet to be sent reliably on the specified channel.
+    ///
+    /// Packets queued on different channels are interleaved according to the `ChannelPolicy` configured on the `HostBuilder`,
+    /// so a channel with a lot of queued data doesn't hold back the others.
+    pub fn send_on(&mut self, channel: u8, packet: impl Into<Arc<[u8]>>) -> Result<(), SendError> {
+        self.send_with(packet, SendOptions::new().channel(channel))
+    }
+
+    /// Queues a packet to be sent with the specified options.
+    ///
+    /// Fails with `SendError::InvalidChannel` if the channel isn't lower than `Peer::channels`.
+    /// The packet can be passed as an `Arc<[u8]>` to share it with other peers without copying.
+    pub fn send_with(
+        &mut self,
+        packet: impl Into<Arc<[u8]>>,
+        options: impl Into<SendOptions>,
+    ) -> Result<(), SendError> {
+        let packet = packet.into();
+        let options = options.into();
+
+        // Checked first, so that a packet which is too large is reported as such whatever the state of the peer.
+        if packet.len() > self.config.max_packet_size {
+            return Err(SendError::TooLarge);
+        }
+
+        if !matches!(
+            self.state,
+            State::Connecting | State::Connected | State::Waiting(_)
+        ) {
+            return Err(SendError::Disconnected);
+        }
 
-            if processed != 0 {
-                self.last_activity = Instant::now();
+        if options.channel >= self.channels {
+            return Err(SendError::InvalidChannel);
+        }
+
+        let (header, packet) = match self.compress(options.channel, &packet) {
+            Some(compressed) => (&[COMPRESSED][..], Arc::from(compressed)),
+            None if self.config.raw => (&[][..], packet),
+            None => (&[options.channel][..], packet),
+        };
+
+        let queued = self.encode(header, packet, options.delivery)?;
+        if let Some(high_water_mark) = self.config.high_water_mark {
+            let fits =
+                |outgoing: &OutgoingQueue| outgoing.bytes() + queued.len() <= high_water_mark;
+
+            if !fits(&self.outgoing) {
+                match self.config.backpressure {
+                    Backpressure::Reject => return Err(SendError::QueueFull),
+                    Backpressure::DropOldest => {
+                        while !fits(&self.outgoing) && self.outgoing.drop_oldest() {}
+                    }
+                    Backpressure::Disconnect => {
+                        self.close(DisconnectReason::QueueFull);
+                        return Err(SendError::QueueFull);
+                    }
+                }
             }
         }
 
+        self.outgoing
+            .push(options.channel, options.priority, queued);
         Ok(())
     }
 
-    pub(crate) fn incoming_packets<'a>(&'a mut self) -> impl Iterator<Item = Vec<u8>> + 'a {
-        self.incoming_packets.drain(0..)
+    /// Serializes a message with the format configured on the `HostBuilder` and queues it like `Peer::send`.
+    ///
+    /// Fails with `SendError::Invalid` if the message can't be serialized, or `SendError::NoFormat` if no format is
+    /// configured.
+    #[cfg(feature = "serde")]
+    pub fn send_msg<M>(&mut self, message: &M) -> Result<(), SendError>
+    where
+        M: serde::Serialize,
+    {
+        self.send_msg_with(message, SendOptions::new())
     }
 
-    /// Disconnects this peer.
-    pub fn disconnect(&mut self) {
-        self.stream = None;
+    /// Serializes a message like `Peer::send_msg` and queues it with the specified options like `Peer::send_with`.
+    #[cfg(feature = "serde")]
+    pub fn send_msg_with<M>(
+        &mut self,
+        message: &M,
+        options: impl Into<SendOptions>,
+    ) -> Result<(), SendError>
+    where
+        M: serde::Serialize,
+    {
+        let format = self.config.format.as_ref().ok_or(SendError::NoFormat)?;
+        let packet = format.serialize(message).map_err(|_| SendError::Invalid)?;
+
+        self.send_with(packet, options)
+    }
+}
+
+impl<T> Peer<T> {
+    /// Returns the number of packets waiting in the outgoing queue.
+    ///
+    /// A packet which is already being written or an unacknowledged UDP packet isn't included.
+    pub fn queued_packets(&self) -> usize {
+        self.outgoing.packets()
+    }
+
+    /// Returns the number of bytes waiting in the outgoing queue, including the framing.
+    ///
+    /// A packet which is already being written or an unacknowledged UDP packet isn't included.
+    pub fn queued_bytes(&self) -> usize {
+        self.outgoing.bytes()
+    }
+
+    /// Returns the number of channels packets can be sent on.
+    ///
+    /// Until the remote side announces its channel count, this is the count configured on the `HostBuilder`,
+    /// afterwards it's the lower of the two. Packets queued on channels the remote side doesn't have are discarded.
+    pub fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    /// Returns the protocol version agreed on with the remote side, the highest one supported by both.
+    ///
+    /// None until the connection is established, which is always the case for raw hosts.
+    /// See `HostBuilder::protocol_version`.
+    pub fn protocol_version(&self) -> Option<u16> {
+        self.version
+    }
+
+    /// Returns the feature flags supported by both sides, see `HostBuilder::features`.
+    pub fn features(&self) -> u32 {
+        self.features
     }
 
-    /// Queues a packet to be sent.
-    pub fn send(&mut self, packet: Vec<u8>) {
-        self.outgoing_packets.push_back(packet);
+    /// Returns the algorithm packets sent to the remote side are compressed with, see `HostBuilder::compression`.
+    ///
+    /// None until the connection is established, or if the remote side can't decompress the configured algorithm.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Returns the smoothed round-trip time to the remote side, measured with pings.
+    ///
+    /// None until the first pong is received, which is always the case for raw hosts.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.map(|(rtt, _)| rtt)
+    }
+
+    /// Returns how much the round-trip time varies, None until the first pong is received.
+    pub fn rtt_variance(&self) -> Option<Duration> {
+        self.rtt.map(|(_, variance)| variance)
+    }
+
+    /// Returns the maximum size of a packet that can be sent to or received from this peer.
+    pub fn max_packet_size(&self) -> usize {
+        self.config.max_packet_size
+    }
+
+    /// Overrides the maximum packet size configured on the `HostBuilder` for this peer.
+    ///
+    /// If the remote side announces a larger packet, the peer is disconnected with `DisconnectReason::PacketTooLarge`.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.config.max_packet_size = max_packet_size;
+    }
+
+    /// Returns the rate limit of packets received from this peer, if any.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.as_ref().map(TokenBucket::limit)
+    }
+
+    /// Overrides the rate limit configured on the `HostBuilder` for this peer, None removes the limit.
+    ///
+    /// The limit of the IP address of the peer still applies.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit.map(|limit| TokenBucket::new(limit, Instant::now()));
     }
 
     /// Returns the socket address of the remote side.
+    ///
+    /// While the host name passed to `Host::connect_host` is being resolved, the IP address is unspecified.
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
 
+    /// Returns true while the host name passed to `Host::connect_host` is being resolved.
+    pub fn resolving(&self) -> bool {
+        matches!(self.connection, Connection::Idle(_)) && matches!(self.state, State::Connecting)
+    }
+
+    /// Returns true while the peer waits to reconnect after losing its connection.
+    pub fn reconnecting(&self) -> bool {
+        matches!(self.state, State::Waiting(_))
+    }
+
+    /// Makes the peer reconnect with this policy after losing its connection, instead of being disconnected.
+    ///
+    /// A `Reconnecting` event is generated once the connection is lost, followed by `Reconnected` once it's established
+    /// again. Meanwhile packets can be queued, the ones which weren't sent over the lost connection are discarded.
+    /// If the policy gives up, a `Disconnect` event with the reason of the last failed attempt is generated.
+    ///
+    /// Failed attempts to establish the first connection are retried as well. Peers are never reconnected after
+    /// `Peer::disconnect`, nor if they were accepted by a server.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
     /// Returns a reference to associated data.
     pub fn data(&self) -> &T {
         &self.data
@@ -210,9 +1247,9 @@ where
         &mut self.data
     }
 
-    /// Returns the index of this peer in the `Host` structure.
-    pub fn idx(&self) -> usize {
-        self.idx
+    /// Returns the handle of this peer in the `Host` structure.
+    pub fn id(&self) -> PeerId {
+        self.id
     }
 }
 
@@ -224,19 +1261,226 @@ where
         f.debug_struct("Peer")
             .field("addr", &self.addr)
             .field("data", &self.data)
-            .field("idx", &self.idx)
+            .field("id", &self.id)
             .finish()
     }
 }
 
-enum ReadState {
-    Size1(u8),
-    Size2(u8, u8),
-    Size3(u8, u8, u8),
-    Packet(Vec<u8>, usize),
+/// How a packet is sent, passed to `Peer::send_with` and `Host::broadcast_with`.
+///
+/// The default is a reliable packet of normal priority on channel 0. A `Delivery` or a `Priority` can be used
+/// in place of the options to change just that.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    channel: u8,
+    delivery: Delivery,
+    priority: Priority,
+}
+
+impl SendOptions {
+    /// Creates the default options.
+    pub fn new() -> SendOptions {
+        SendOptions::default()
+    }
+
+    /// Sets the channel the packet is sent on.
+    pub fn channel(mut self, channel: u8) -> SendOptions {
+        self.channel = channel;
+        self
+    }
+
+    /// Sets the delivery of the packet.
+    pub fn delivery(mut self, delivery: Delivery) -> SendOptions {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Sets the priority of the packet.
+    pub fn priority(mut self, priority: Priority) -> SendOptions {
+        self.priority = priority;
+        self
+    }
+}
+
+impl From<Delivery> for SendOptions {
+    fn from(delivery: Delivery) -> SendOptions {
+        SendOptions::new().delivery(delivery)
+    }
+}
+
+impl From<Priority> for SendOptions {
+    fn from(priority: Priority) -> SendOptions {
+        SendOptions::new().priority(priority)
+    }
+}
+
+/// How a packet is delivered to the remote side.
+///
+/// Only matters for hosts using `Transport::Udp`, over TCP every packet is delivered reliably and in order.
+/// Over UDP, reliable packets are delivered in the order they were sent regardless of their channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// The packet is retransmitted until it's acknowledged and delivered in order with other reliable packets.
+    #[default]
+    Reliable,
+    /// The packet may be lost and is dropped if a newer sequenced packet has already been delivered.
+    Sequenced,
+    /// The packet may be lost or delivered out of order.
+    Unreliable,
+}
+
+/// The priority of a packet in the outgoing queue.
+///
+/// The writer always picks the packet of the highest priority queued, packets of the same priority on the same channel
+/// are sent in order. Packets which were already handed to the writer are never preempted, over TCP these are at most
+/// a small batch written with a single system call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// For bulk data which can wait.
+    Low,
+    #[default]
+    Normal,
+    /// For urgent messages such as pings, kicks or resynchronizations.
+    High,
+}
+
+/// An error returned when a packet can't be queued to be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The peer is disconnected or is being disconnected.
+    Disconnected,
+    /// The packet is larger than the maximum packet size of the peer.
+    TooLarge,
+    /// The packet can't be encoded by the codec, for example because it's empty or contains a delimiter,
+    /// or the message can't be serialized by the format.
+    Invalid,
+    /// The channel isn't available for the peer.
+    InvalidChannel,
+    /// The outgoing queue of the peer is over the high-water mark configured on the `HostBuilder`.
+    QueueFull,
+    /// A message can't be sent because no format is configured on the `HostBuilder`. Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    NoFormat,
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SendError::Disconnected => write!(f, "peer is disconnected"),
+            SendError::TooLarge => write!(f, "packet is too large"),
+            SendError::Invalid => write!(f, "packet can't be encoded"),
+            SendError::InvalidChannel => write!(f, "channel doesn't exist"),
+            SendError::QueueFull => write!(f, "outgoing queue is full"),
+            #[cfg(feature = "serde")]
+            SendError::NoFormat => write!(f, "no format is configured"),
+        }
+    }
+}
+
+impl error::Error for SendError {}
+
+/// What happens to a packet that would fill the outgoing queue of a peer over the high-water mark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// The packet is rejected with `SendError::QueueFull`.
+    Reject,
+    /// The oldest queued packets are discarded to make room for the packet.
+    ///
+    /// The packet is queued even if it doesn't fit into an empty queue.
+    DropOldest,
+    /// The packet is rejected with `SendError::QueueFull` and the peer is disconnected with `DisconnectReason::QueueFull`.
+    Disconnect,
+}
+
+/// Configuration of peers shared by the whole `Host`.
+#[derive(Clone)]
+pub(crate) struct PeerConfig {
+    pub(crate) linger: Duration,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) max_packet_size: usize,
+    pub(crate) read_size: usize,
+    pub(crate) codec: Arc<dyn Codec>,
+    pub(crate) channels: u8,
+    pub(crate) channel_policy: ChannelPolicy,
+    pub(crate) raw: bool,
+    pub(crate) high_water_mark: Option<usize>,
+    pub(crate) backpressure: Backpressure,
+    pub(crate) app_id: u32,
+    pub(crate) protocol_version: u16,
+    pub(crate) min_protocol_version: u16,
+    pub(crate) features: u32,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) compression_threshold: usize,
+    pub(crate) auth_token: Option<Arc<[u8]>>,
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) rate_limit_action: RateLimitAction,
+    #[cfg(feature = "serde")]
+    pub(crate) format: Option<Arc<dyn Format>>,
+    #[cfg(feature = "serde")]
+    pub(crate) decode_error_action: DecodeErrorAction,
+}
+
+/// An error that occured while processing a peer.
+pub(crate) enum ProcessError {
+    /// The peer has to be disconnected.
+    Disconnect(DisconnectReason),
+    /// An unexpected error which is propagated to the user.
+    Io(Error),
+}
+
+impl From<Error> for ProcessError {
+    fn from(err: Error) -> ProcessError {
+        let reason = match err.kind() {
+            ErrorKind::InvalidData => DisconnectReason::InvalidData(err),
+            ErrorKind::ConnectionRefused => DisconnectReason::Refused(err),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                DisconnectReason::Reset(err)
+            }
+            // The remote side can't be reached anymore, which doesn't concern the other peers.
+            ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::TimedOut => DisconnectReason::Unreachable(err),
+            _ => return ProcessError::Io(err),
+        };
+
+        ProcessError::Disconnect(reason)
+    }
+}
+
+/// The transport of a peer.
+pub(crate) enum Connection {
+    Tcp(TcpConnection),
+    Udp(UdpConnection),
+    /// No connection attempt is running yet, because the host name of the remote side is being resolved
+    /// or because the peer waits to reconnect. Packets are framed for this transport meanwhile.
+    Idle(Transport),
+}
+
+/// The progress of the authentication of a peer.
+enum Auth {
+    /// No authentication is required, or it has succeeded.
+    Done,
+    /// The client waits for the server to accept its token.
+    Waiting,
+    /// The server waits for the token of the client.
+    Expected,
+    /// The server has received the token, which has to be checked by the host.
+    Received(Vec<u8>),
 }
 
-struct WriteState {
-    data: Vec<u8>,
-    done: usize,
+enum State {
+    /// Waiting for the remote side to accept the connection.
+    Connecting,
+    Connected,
+    /// The outgoing packets are being flushed until the deadline, after which the connection is closed.
+    Closing(Instant),
+    /// The connection was lost and the peer will reconnect at this time.
+    Waiting(Instant),
+    /// The connection is closed and a `Disconnect` event is yet to be generated.
+    Disconnecting(DisconnectReason),
+    /// The `Disconnect` event has been generated.
+    Disconnected,
 }
@@ -0,0 +1,83 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How a peer connected with `Host::connect` reconnects after losing its connection, see `Peer::set_reconnect_policy`.
+///
+/// The delay before an attempt starts at the initial delay and doubles with every failed attempt, up to the maximum delay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    jitter: f64,
+}
+
+impl ReconnectPolicy {
+    /// Creates the default policy.
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy::default()
+    }
+
+    /// Sets the delay before the first attempt.
+    ///
+    /// The default is 500 milliseconds.
+    pub fn initial_delay(mut self, initial_delay: Duration) -> ReconnectPolicy {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// Sets the maximum delay between two attempts.
+    ///
+    /// The default is 30 seconds.
+    pub fn max_delay(mut self, max_delay: Duration) -> ReconnectPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sets the number of failed attempts after which the peer is disconnected.
+    ///
+    /// The default is no limit.
+    pub fn max_attempts(mut self, max_attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Sets the fraction of every delay which is random, so that clients losing the same server don't reconnect in lockstep.
+    ///
+    /// The default is 0.5. Panics if `jitter` isn't between 0 and 1.
+    pub fn jitter(mut self, jitter: f64) -> ReconnectPolicy {
+        assert!((0.0..=1.0).contains(&jitter), "invalid jitter {}", jitter);
+
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns true if another attempt can be made after `attempts` failed ones.
+    pub(crate) fn allows(&self, attempts: u32) -> bool {
+        self.max_attempts
+            .is_none_or(|max_attempts| attempts < max_attempts)
+    }
+
+    /// Returns the delay before the attempt following `attempts` failed ones.
+    pub(crate) fn delay(&self, attempts: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempts))
+            .min(self.max_delay);
+
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            jitter: 0.5,
+        }
+    }
+}
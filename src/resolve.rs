@@ -0,0 +1,74 @@
+use super::peer::PeerId;
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::io::Error;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+/// Resolves host names passed to `Host::connect_host`.
+///
+/// Lookups may block, they are run on a helper thread so that they don't stall the event loop.
+pub trait Resolver: Send + Sync {
+    /// Returns the addresses of `host`, in the order they should be tried.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+}
+
+/// Resolves host names using the resolver of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// The result of a lookup made on behalf of a peer.
+pub(crate) type Lookup = (PeerId, Result<Vec<SocketAddr>, Error>);
+
+/// Lookups running on helper threads, which wake up the `Poll` of the host once they finish.
+pub(crate) struct Lookups {
+    // Deregistered when dropped.
+    _registration: Registration,
+    set_readiness: SetReadiness,
+    sender: Sender<Lookup>,
+    receiver: Receiver<Lookup>,
+}
+
+impl Lookups {
+    pub(crate) fn new(poll: &Poll, token: Token) -> Result<Lookups, Error> {
+        let (registration, set_readiness) = Registration::new2();
+        poll.register(&registration, token, Ready::readable(), PollOpt::edge())?;
+
+        let (sender, receiver) = mpsc::channel();
+        Ok(Lookups {
+            _registration: registration,
+            set_readiness,
+            sender,
+            receiver,
+        })
+    }
+
+    /// Starts resolving `host` for the peer with this handle.
+    pub(crate) fn start(&self, resolver: Arc<dyn Resolver>, id: PeerId, host: String, port: u16) {
+        let sender = self.sender.clone();
+        let set_readiness = self.set_readiness.clone();
+
+        thread::spawn(move || {
+            let result = resolver.resolve(&host, port);
+
+            // The host might be gone already.
+            if sender.send((id, result)).is_ok() {
+                let _ = set_readiness.set_readiness(Ready::readable());
+            }
+        });
+    }
+
+    /// Returns the lookups which finished since the last call.
+    pub(crate) fn finished(&self) -> Vec<Lookup> {
+        // Reset first, so that a lookup finishing in the meantime wakes the host up again.
+        let _ = self.set_readiness.set_readiness(Ready::empty());
+        self.receiver.try_iter().collect()
+    }
+}
@@ -0,0 +1,512 @@
+use super::channel::{Framing, OutgoingQueue, Queued};
+use super::codec::{Codec, Decode};
+use super::event::DisconnectReason;
+use super::peer::{ProcessError, SendError};
+#[cfg(feature = "tls")]
+use super::tls::TlsSession;
+use iovec::IoVec;
+use mio::net::TcpStream;
+use mio::Ready;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read};
+use std::net::Shutdown;
+
+/// The maximum number of frames written with a single system call.
+const MAX_BATCH_FRAMES: usize = 64;
+/// Frames stop being added to a batch once it's at least this large.
+const MAX_BATCH_SIZE: usize = 64 * 1024;
+/// The maximum size of the read buffer allocated upfront for a packet announced by the remote side.
+///
+/// Larger packets grow the buffer as their data arrives, so that a peer can't make us allocate memory it never fills.
+const MAX_PREALLOCATION: usize = 1024 * 1024;
+
+/// A connection over a TCP stream, with packets delimited by a codec.
+///
+/// Every frame may be preceded by a header of a fixed size, which isn't seen by the codec.
+pub(crate) struct TcpConnection {
+    stream: Option<TcpStream>,
+    ready: Ready,
+    /// Frames taken from the outgoing queue which are being written, in order.
+    writing: VecDeque<Queued>,
+    /// The number of bytes of the first frame in `writing` which were already written.
+    written: usize,
+    read_buffer: ReadBuffer,
+    read_closed: bool,
+    /// Reading is paused, the remote side is slowed down by flow control meanwhile.
+    read_paused: bool,
+    /// The stream is waiting for the remote side to accept the connection.
+    connecting: bool,
+    /// Encrypts the stream, the connection is established once the handshake finishes.
+    #[cfg(feature = "tls")]
+    tls: Option<Box<TlsSession>>,
+}
+
+impl TcpConnection {
+    pub(crate) fn new(stream: Option<TcpStream>) -> TcpConnection {
+        TcpConnection {
+            stream,
+            ready: Ready::empty(),
+            writing: VecDeque::new(),
+            written: 0,
+            read_buffer: ReadBuffer {
+                data: Vec::new(),
+                start: 0,
+                end: 0,
+            },
+            read_closed: false,
+            read_paused: false,
+            connecting: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Creates a connection over a stream which is still connecting to the remote side.
+    pub(crate) fn connect(stream: TcpStream) -> TcpConnection {
+        TcpConnection {
+            connecting: true,
+            ..TcpConnection::new(Some(stream))
+        }
+    }
+
+    /// Encrypts the stream with this session, nothing must have been transferred yet.
+    #[cfg(feature = "tls")]
+    pub(crate) fn start_tls(&mut self, tls: TlsSession) {
+        self.tls = Some(Box::new(tls));
+    }
+
+    pub(crate) fn update_ready(&mut self, ready: Ready) {
+        self.ready.insert(ready);
+    }
+
+    /// Writes frames from the outgoing queue and reads incoming ones. Returns whether any data was transferred.
+    ///
+    /// Received frames include their header of `header_size` bytes, `max_frame_size` includes it as well.
+    /// At least `read_size` bytes are read from the stream at once.
+    pub(crate) fn process(
+        &mut self,
+        codec: &dyn Codec,
+        header_size: usize,
+        max_frame_size: usize,
+        read_size: usize,
+        outgoing: &mut OutgoingQueue,
+        frames: &mut VecDeque<Vec<u8>>,
+    ) -> Result<bool, ProcessError> {
+        let mut processed = 0usize;
+
+        if self.connecting {
+            // The outcome of a connection attempt is signalled by any readiness.
+            if self.ready.is_empty() {
+                return Ok(false);
+            }
+
+            self.finish_connect()?;
+            if self.connecting {
+                return Ok(false);
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        if let (Some(ref mut stream), Some(ref mut tls)) = (&mut self.stream, &mut self.tls) {
+            if tls.handshaking() {
+                processed += tls.handshake(stream, &mut self.ready)?;
+                if tls.handshaking() {
+                    return Ok(processed != 0);
+                }
+            }
+        }
+
+        if self.ready.is_writable() {
+            processed += self.process_writable(outgoing)?;
+        }
+
+        if (self.ready.is_readable() || self.tls_buffered())
+            && !self.read_closed
+            && !self.read_paused
+        {
+            processed +=
+                self.process_readable(codec, header_size, max_frame_size, read_size, frames)?;
+        }
+
+        Ok(processed != 0)
+    }
+
+    /// Returns true once the remote side has accepted the connection and the TLS handshake, if any, has finished.
+    pub(crate) fn established(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.as_ref().is_some_and(|tls| tls.handshaking()) {
+            return false;
+        }
+
+        !self.connecting
+    }
+
+    /// Returns true if no frame is being written.
+    pub(crate) fn flushed(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.as_ref().is_some_and(|tls| tls.wants_write()) {
+            return false;
+        }
+
+        self.writing.is_empty()
+    }
+
+    /// Returns true if decrypted data can be read without the stream being readable.
+    fn tls_buffered(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.as_ref().is_some_and(|tls| tls.buffered()) {
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns true if the remote side won't send anything anymore.
+    pub(crate) fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Stops or resumes reading from the stream.
+    pub(crate) fn pause_reading(&mut self, paused: bool) {
+        self.read_paused = paused;
+    }
+
+    /// Shuts down the write half of the stream.
+    pub(crate) fn shutdown(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "tls")]
+        if let (Some(ref mut stream), Some(ref mut tls)) = (&mut self.stream, &mut self.tls) {
+            tls.close(stream)?;
+        }
+
+        if let Some(ref stream) = self.stream {
+            if let Err(err) = stream.shutdown(Shutdown::Write) {
+                if err.kind() != ErrorKind::NotConnected {
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.stream = None;
+    }
+
+    /// Checks whether the connection attempt has finished, returns an error if it failed.
+    fn finish_connect(&mut self) -> Result<(), ProcessError> {
+        let stream = match self.stream {
+            Some(ref stream) => stream,
+            None => return Ok(()),
+        };
+
+        if let Some(err) = stream.take_error()? {
+            return Err(ProcessError::Disconnect(connect_reason(err)));
+        }
+
+        match stream.peer_addr() {
+            Ok(_) => self.connecting = false,
+            // Woken up spuriously, wait for the next readiness.
+            Err(ref err) if err.kind() == ErrorKind::NotConnected => self.ready = Ready::empty(),
+            Err(err) => return Err(ProcessError::Disconnect(connect_reason(err))),
+        }
+
+        Ok(())
+    }
+
+    /// Writes as many frames as possible, coalescing small frames into a single system call.
+    fn process_writable(&mut self, outgoing: &mut OutgoingQueue) -> Result<usize, Error> {
+        let mut processed = 0usize;
+
+        if let Some(ref mut stream) = self.stream {
+            // Encrypted data left over from the last time goes first.
+            #[cfg(feature = "tls")]
+            if let Some(ref mut tls) = self.tls {
+                match tls.flush(stream) {
+                    Ok(n) => processed += n,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                        self.ready.remove(Ready::writable());
+                        return Ok(processed);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            loop {
+                // Only a frame which was partially written is left from the last batch, it has to be finished first.
+                let mut size = self.writing.iter().map(Queued::len).sum::<usize>() - self.written;
+                while self.writing.len() < MAX_BATCH_FRAMES && size < MAX_BATCH_SIZE {
+                    match outgoing.pop(|_| true) {
+                        Some(queued) => {
+                            size += queued.len();
+                            self.writing.push_back(queued);
+                        }
+                        None => break,
+                    }
+                }
+
+                if self.writing.is_empty() {
+                    break;
+                }
+
+                // Skip the part of the first frame where the previous write ended.
+                let mut offset = self.written;
+                let bufs = self
+                    .writing
+                    .iter()
+                    .flat_map(|queued| queued.parts())
+                    .filter_map(|part| {
+                        if offset >= part.len() {
+                            offset -= part.len();
+                            return None;
+                        }
+
+                        let part = &part[offset..];
+                        offset = 0;
+                        IoVec::from_bytes(part)
+                    })
+                    .collect::<Vec<_>>();
+
+                #[cfg(feature = "tls")]
+                let result = match self.tls {
+                    Some(ref mut tls) => tls.write_bufs(stream, &bufs),
+                    None => stream.write_bufs(&bufs),
+                };
+                #[cfg(not(feature = "tls"))]
+                let result = stream.write_bufs(&bufs);
+
+                let n = match result {
+                    Ok(n) => n,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                        self.ready.remove(Ready::writable());
+                        unpop_unstarted(&mut self.writing, self.written, outgoing);
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                processed += n;
+
+                // Remove the written frames, keeping the rest of a partially written one for the next time.
+                let mut left = n;
+                while let Some(queued) = self.writing.front() {
+                    let remaining = queued.len() - self.written;
+                    if left < remaining {
+                        self.written += left;
+                        break;
+                    }
+
+                    left -= remaining;
+                    self.written = 0;
+                    self.writing.pop_front();
+                }
+
+                // Packets of a higher priority queued until the socket accepts more data can overtake the rest.
+                unpop_unstarted(&mut self.writing, self.written, outgoing);
+
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+
+        Ok(processed)
+    }
+
+    fn process_readable(
+        &mut self,
+        codec: &dyn Codec,
+        header_size: usize,
+        max_frame_size: usize,
+        read_size: usize,
+        frames: &mut VecDeque<Vec<u8>>,
+    ) -> Result<usize, ProcessError> {
+        let mut processed = 0usize;
+        // The size of the incomplete frame at the start of the buffer, if announced.
+        let mut announced = None;
+
+        while let Some(ref mut stream) = self.stream {
+            // Make room for the rest of an announced frame at once, so that large packets aren't reallocated
+            // over and over as they arrive.
+            let wanted = announced
+                .map(|size: usize| size.min(MAX_PREALLOCATION))
+                .unwrap_or(0)
+                .saturating_sub(self.read_buffer.len())
+                .max(read_size);
+
+            let buf = self.read_buffer.spare(wanted);
+            #[cfg(feature = "tls")]
+            let result = match self.tls {
+                Some(ref mut tls) => tls.read(stream, buf),
+                None => stream.read(buf),
+            };
+            #[cfg(not(feature = "tls"))]
+            let result = stream.read(buf);
+
+            let n = match result {
+                Ok(0) => {
+                    // The remote side won't send anything anymore, but might still be reading.
+                    self.read_closed = true;
+                    break;
+                }
+                Ok(n) => n,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.ready.remove(Ready::readable());
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            self.read_buffer.end += n;
+            announced = self.decode(codec, header_size, max_frame_size, frames)?;
+
+            processed += n;
+        }
+
+        Ok(processed)
+    }
+
+    /// Moves all complete frames from the read buffer to the incoming queue.
+    ///
+    /// Returns the size of the remaining incomplete frame including its header, if it was announced already.
+    fn decode(
+        &mut self,
+        codec: &dyn Codec,
+        header_size: usize,
+        max_frame_size: usize,
+        frames: &mut VecDeque<Vec<u8>>,
+    ) -> Result<Option<usize>, ProcessError> {
+        let mut start = 0;
+        let mut announced = None;
+
+        let src = self.read_buffer.as_slice();
+        while src.len() - start >= header_size {
+            let src = &src[start..];
+            match codec.decode(&src[header_size..])? {
+                Decode::Frame { payload, len } => {
+                    let size = header_size + payload.len();
+                    if size > max_frame_size {
+                        return Err(ProcessError::Disconnect(DisconnectReason::PacketTooLarge(
+                            size,
+                        )));
+                    }
+
+                    let mut frame = Vec::with_capacity(size);
+                    frame.extend_from_slice(&src[..header_size]);
+                    frame.extend_from_slice(
+                        &src[header_size + payload.start..header_size + payload.end],
+                    );
+
+                    frames.push_back(frame);
+                    start += header_size + len;
+                }
+                Decode::Incomplete(size) => {
+                    let size = size.map(|size| header_size.saturating_add(size));
+                    if let Some(size) = size.filter(|size| *size > max_frame_size) {
+                        return Err(ProcessError::Disconnect(DisconnectReason::PacketTooLarge(
+                            size,
+                        )));
+                    }
+
+                    announced = size;
+                    break;
+                }
+            }
+        }
+
+        self.read_buffer.consume(start);
+        Ok(announced)
+    }
+}
+
+/// Bytes read from the stream which weren't decoded yet.
+///
+/// The bytes are kept in `data[start..end]`, the stream is read directly into the space following them.
+struct ReadBuffer {
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl ReadBuffer {
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the space following the buffered bytes, making sure it's at least `size` bytes large.
+    fn spare(&mut self, size: usize) -> &mut [u8] {
+        // Give back the memory of an unusually large packet once it's gone.
+        if self.start == self.end && self.data.len() > 2 * size {
+            self.data = vec![0; size];
+        }
+
+        if self.data.len() - self.end < size {
+            self.data.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+
+            if self.data.len() - self.end < size {
+                self.data.resize(self.end + size, 0);
+            }
+        }
+
+        &mut self.data[self.end..]
+    }
+
+    /// Removes `n` bytes from the start.
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+}
+
+/// Puts the frames of a batch which weren't started back into the outgoing queue.
+///
+/// `written` bytes of the first frame are on the wire, so it stays unless nothing was written.
+fn unpop_unstarted(writing: &mut VecDeque<Queued>, written: usize, outgoing: &mut OutgoingQueue) {
+    let started = if written > 0 { 1 } else { 0 };
+    while writing.len() > started {
+        if let Some(queued) = writing.pop_back() {
+            outgoing.unpop(queued);
+        }
+    }
+}
+
+/// Returns the reason of disconnection for an error which occured while connecting.
+pub(crate) fn connect_reason(err: Error) -> DisconnectReason {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => DisconnectReason::Refused(err),
+        _ => DisconnectReason::Unreachable(err),
+    }
+}
+
+/// Encodes the framing of a packet, which is preceded by `header`.
+///
+/// `scratch` is used to avoid allocations.
+pub(crate) fn framing(
+    codec: &dyn Codec,
+    header: &[u8],
+    packet: &[u8],
+    scratch: &mut Vec<u8>,
+) -> Result<(Framing, Framing), SendError> {
+    scratch.clear();
+    scratch.extend_from_slice(header);
+    codec
+        .encode_header(packet, scratch)
+        .map_err(|_| SendError::Invalid)?;
+    let header = Framing::new(scratch);
+
+    scratch.clear();
+    codec
+        .encode_trailer(packet, scratch)
+        .map_err(|_| SendError::Invalid)?;
+
+    Ok((header, Framing::new(scratch)))
+}
@@ -1,8 +1,11 @@
 use super::*;
 
-use std::net::Ipv4Addr;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 
 const PORT: u16 = 8000;
 
@@ -14,7 +17,7 @@ fn test_packet_order() {
     let handle = {
         let barrier = barrier.clone();
         thread::spawn(move || {
-            let host = Host::<()>::builder().server((Ipv4Addr::LOCALHOST, PORT).into());
+            let host = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT).into());
 
             barrier.wait();
 
@@ -26,27 +29,1964 @@ fn test_packet_order() {
 
             for packet in PACKETS {
                 let event = host.process_blocking().unwrap();
-                assert_eq!(event.kind, EventKind::Receive(packet.to_vec()));
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: packet.to_vec()
+                    }
+                );
+
+                if packet == PACKETS.last().unwrap() {
+                    event.peer.disconnect();
+                }
             }
 
             let event = host.process_blocking().unwrap();
-            assert_eq!(event.kind, EventKind::Disconnect);
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
         })
     };
 
     barrier.wait();
 
     let mut host = Host::<()>::builder().client().unwrap();
-    let idx = host.connect((Ipv4Addr::LOCALHOST, PORT)).unwrap().idx();
+    let id = host.connect((Ipv4Addr::LOCALHOST, PORT)).unwrap().id();
     for packet in PACKETS {
-        host[idx].send(packet.to_vec());
+        host[id].send(packet.to_vec()).unwrap();
+    }
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_stale_peer_id() {
+    let mut host = Host::<()>::builder()
+        .timeout(Duration::from_millis(100))
+        .client()
+        .unwrap();
+
+    // Nobody listens on this port, the peer will be disconnected shortly.
+    let first = host.connect((Ipv4Addr::LOCALHOST, PORT + 1)).unwrap().id();
+    loop {
+        let event = host.process_blocking().unwrap();
+        if let EventKind::Disconnect(_) = event.kind {
+            break;
+        }
+    }
+
+    // Let the host remove the disconnected peer.
+    host.process(Duration::from_millis(0)).unwrap();
+    assert!(host.peer(first).is_none());
+
+    let second = host.connect((Ipv4Addr::LOCALHOST, PORT + 1)).unwrap().id();
+    assert_ne!(first, second);
+    assert!(host.peer(first).is_none());
+    assert!(host.peer(second).is_some());
+}
+
+#[test]
+fn test_local_disconnect_reason() {
+    let _server = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT + 2).into()).unwrap();
+    let mut host = Host::<()>::client().unwrap();
+
+    host.connect((Ipv4Addr::LOCALHOST, PORT + 2))
+        .unwrap()
+        .disconnect();
+
+    // Disconnected before the connection was established.
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+}
+
+#[test]
+fn test_disconnect_later() {
+    // Large enough not to fit into the socket buffers at once.
+    let packet = vec![0xab; 1 << 20];
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        let packet = packet.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder().server((Ipv4Addr::LOCALHOST, PORT + 3).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            event.peer.send(packet).unwrap();
+            event.peer.disconnect_later();
+            assert_eq!(event.peer.send(vec![1]), Err(SendError::Disconnected));
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    host.connect((Ipv4Addr::LOCALHOST, PORT + 3)).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Receive { channel: 0, packet });
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_close() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder().server((Ipv4Addr::LOCALHOST, PORT + 4).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(
+                event.kind,
+                EventKind::Receive {
+                    channel: 0,
+                    packet: b"bye".to_vec()
+                }
+            );
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 4)).unwrap();
+    peer.send(b"bye".to_vec()).unwrap();
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_max_packet_size() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .max_packet_size(16)
+                .server((Ipv4Addr::LOCALHOST, PORT + 5).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+            assert_eq!(event.peer.send(vec![0; 17]), Err(SendError::TooLarge));
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(
+                event.kind,
+                EventKind::Disconnect(DisconnectReason::PacketTooLarge(17))
+            );
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 5)).unwrap();
+    peer.send(vec![0; 17]).unwrap();
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_codecs() {
+    fn round_trip(codec: &dyn Codec, packets: &[&[u8]]) {
+        let mut data = Vec::new();
+        for packet in packets {
+            codec.encode_header(packet, &mut data).unwrap();
+            data.extend_from_slice(packet);
+            codec.encode_trailer(packet, &mut data).unwrap();
+        }
+
+        let mut start = 0;
+        for packet in packets {
+            // Every prefix of the frame has to be incomplete.
+            let (payload, len) = match codec.decode(&data[start..]).unwrap() {
+                Decode::Frame { payload, len } => (payload, len),
+                Decode::Incomplete(_) => panic!("incomplete frame"),
+            };
+            for end in start..start + len {
+                match codec.decode(&data[start..end]).unwrap() {
+                    Decode::Incomplete(_) => {}
+                    Decode::Frame { .. } => panic!("frame decoded too early"),
+                }
+            }
+
+            assert_eq!(&data[start + payload.start..start + payload.end], *packet);
+            start += len;
+        }
+
+        assert_eq!(start, data.len());
+    }
+
+    let long = vec![0x0a; 300];
+    round_trip(&U32Codec, &[b"a", b"hello", &long]);
+    round_trip(&U16Codec, &[b"", b"hello", &long]);
+    round_trip(&VarintCodec, &[b"", b"hello", &long, &vec![1; 70000]]);
+    round_trip(&LineCodec, &[b"", b"hello", b"world\r"]);
+
+    assert!(U32Codec.encode_header(b"", &mut Vec::new()).is_err());
+    assert!(U16Codec
+        .encode_header(&[0; 70000], &mut Vec::new())
+        .is_err());
+    assert!(LineCodec.encode_header(b"a\nb", &mut Vec::new()).is_err());
+    assert!(U32Codec.decode(&[0, 0, 0, 0]).is_err());
+    assert!(VarintCodec.decode(&[0xff; 11]).is_err());
+    assert_eq!(
+        U32Codec.decode(&[0, 0, 1, 0, 1]).unwrap(),
+        Decode::Incomplete(Some(256))
+    );
+}
+
+#[test]
+fn test_line_codec() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .codec(LineCodec)
+                .server((Ipv4Addr::LOCALHOST, PORT + 6).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(
+                event.kind,
+                EventKind::Receive {
+                    channel: 0,
+                    packet: b"hello".to_vec()
+                }
+            );
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(
+                event.kind,
+                EventKind::Receive {
+                    channel: 0,
+                    packet: b"".to_vec()
+                }
+            );
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::builder().codec(LineCodec).client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 6)).unwrap();
+    peer.send(b"hello".to_vec()).unwrap();
+    peer.send(b"".to_vec()).unwrap();
+    assert_eq!(peer.send(b"a\nb".to_vec()), Err(SendError::Invalid));
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_udp_delivery() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .transport(Transport::Udp)
+                .server((Ipv4Addr::LOCALHOST, PORT + 7).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            for packet in &[&b"first"[..], b"second", b"sequenced", b"unreliable"] {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: packet.to_vec()
+                    }
+                );
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::builder()
+        .transport(Transport::Udp)
+        .client()
+        .unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 7)).unwrap();
+    peer.send(b"first".to_vec()).unwrap();
+    peer.send(b"second".to_vec()).unwrap();
+    peer.send_with(b"sequenced".to_vec(), Delivery::Sequenced)
+        .unwrap();
+    peer.send_with(b"unreliable".to_vec(), Delivery::Unreliable)
+        .unwrap();
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_udp_new_session() {
+    let mut host = Host::<()>::builder()
+        .transport(Transport::Udp)
+        .server((Ipv4Addr::LOCALHOST, PORT + 35).into())
+        .unwrap();
+
+    // Connects by hand with the given session, then sends a legacy hello as the first reliable packet.
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let connect = |session: u8| {
+        let server = (Ipv4Addr::LOCALHOST, PORT + 35);
+        socket.send_to(&[0, 0, 0, 0, session], server).unwrap();
+        socket
+            .send_to(&[3, 0, 0, 0, session, 0, 0, 0, 0, 0xff, 0, 1], server)
+            .unwrap();
+    };
+
+    connect(1);
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+    let stale = event.peer.id();
+
+    // The client has started over from the same address, the old peer must not keep the address.
+    connect(2);
+    let event = host.process_blocking().unwrap();
+    assert_eq!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::Reset(ErrorKind::ConnectionReset.into()))
+    );
+    assert_eq!(event.peer.id(), stale);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+    assert_ne!(event.peer.id(), stale);
+}
+
+/// Forwards datagrams from `port` to `target` and back, dropping about a third of them.
+///
+/// The drops follow a fixed pseudo-random sequence, a regular pattern could keep hitting the same retransmission.
+fn lossy_proxy(port: u16, target: u16, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    let front = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+    let back = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    back.connect((Ipv4Addr::LOCALHOST, target)).unwrap();
+    front.set_nonblocking(true).unwrap();
+    back.set_nonblocking(true).unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = [0; 65536];
+        let mut client = None;
+        let mut state = 0x2545_f491u32;
+        let mut lose = move || {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.is_multiple_of(3)
+        };
+
+        while !stop.load(Ordering::SeqCst) {
+            let mut idle = true;
+
+            if let Ok((n, addr)) = front.recv_from(&mut buffer) {
+                idle = false;
+                client = Some(addr);
+                if !lose() {
+                    let _ = back.send(&buffer[..n]);
+                }
+            }
+
+            if let Ok(n) = back.recv(&mut buffer) {
+                idle = false;
+                if let Some(client) = client.filter(|_| !lose()) {
+                    let _ = front.send_to(&buffer[..n], client);
+                }
+            }
+
+            if idle {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    })
+}
+
+#[test]
+fn test_udp_reliability() {
+    const PACKETS: u32 = 50;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let proxy = lossy_proxy(PORT + 9, PORT + 8, stop.clone());
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .transport(Transport::Udp)
+                .server((Ipv4Addr::LOCALHOST, PORT + 8).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            for i in 0..PACKETS {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: i.to_be_bytes().to_vec()
+                    }
+                );
+            }
+
+            // Keep acknowledging retransmissions until the client is done.
+            while !stop.load(Ordering::SeqCst) {
+                host.process(Duration::from_millis(10)).unwrap();
+            }
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::builder()
+        .transport(Transport::Udp)
+        .client()
+        .unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 9)).unwrap();
+    for i in 0..PACKETS {
+        peer.send(i.to_be_bytes().to_vec()).unwrap();
     }
+    peer.disconnect_later();
 
     let event = host.process_blocking().unwrap();
     assert_eq!(event.kind, EventKind::Connect);
 
+    // Only generated once every packet is acknowledged.
     let event = host.process_blocking().unwrap();
-    assert_eq!(event.kind, EventKind::Disconnect);
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
 
+    stop.store(true, Ordering::SeqCst);
     handle.join().unwrap();
+    proxy.join().unwrap();
+}
+
+#[test]
+fn test_channels() {
+    const PACKETS: &[(u8, &[u8])] = &[(1, b"asset 1"), (1, b"asset 2"), (0, b"chat")];
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .channels(3)
+                .server((Ipv4Addr::LOCALHOST, PORT + 10).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            // Channels take turns, starting with the first one.
+            for (channel, packet) in &[PACKETS[2], PACKETS[0], PACKETS[1]] {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: *channel,
+                        packet: packet.to_vec()
+                    }
+                );
+                assert_eq!(event.peer.channels(), 2);
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::builder().channels(2).client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 10)).unwrap();
+    for (channel, packet) in PACKETS {
+        peer.send_on(*channel, packet.to_vec()).unwrap();
+    }
+    assert_eq!(
+        peer.send_on(2, b"nope".to_vec()),
+        Err(SendError::InvalidChannel)
+    );
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_raw() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .codec(LineCodec)
+                .raw(true)
+                .channels(2)
+                .server((Ipv4Addr::LOCALHOST, PORT + 11).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+            assert_eq!(event.peer.channels(), 1);
+
+            for packet in &[&b"hello"[..], b"world"] {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: packet.to_vec()
+                    }
+                );
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    // A client which doesn't use asnet.
+    let mut stream = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, PORT + 11)).unwrap();
+    stream.write_all(b"hello\nworld\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_priority() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder().server((Ipv4Addr::LOCALHOST, PORT + 12).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            for packet in &[&b"urgent"[..], b"first", b"second", b"bulk"] {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: packet.to_vec()
+                    }
+                );
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 12)).unwrap();
+    peer.send_with(b"bulk".to_vec(), Priority::Low).unwrap();
+    peer.send(b"first".to_vec()).unwrap();
+    peer.send(b"second".to_vec()).unwrap();
+    peer.send_with(b"urgent".to_vec(), Priority::High).unwrap();
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+
+    // The socket is already backed up when the urgent packet is sent. Channels take turns meanwhile.
+    let builder = Host::<()>::builder().channels(3);
+    let mut server = builder
+        .clone()
+        .server((Ipv4Addr::LOCALHOST, PORT + 36).into())
+        .unwrap();
+    let mut client = builder.client().unwrap();
+    let id = client
+        .connect((Ipv4Addr::LOCALHOST, PORT + 36))
+        .unwrap()
+        .id();
+
+    let start = std::time::Instant::now();
+    let mut connected = 0;
+    while connected < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        for host in &mut [&mut server, &mut client] {
+            if let Some(event) = host.process(Duration::from_millis(10)).unwrap() {
+                assert_eq!(event.kind, EventKind::Connect);
+                connected += 1;
+            }
+        }
+    }
+
+    let numbered = |i: u32| {
+        let mut packet = i.to_be_bytes().to_vec();
+        packet.resize(1024, 0);
+        ((i % 3) as u8, packet)
+    };
+
+    // The client fills the socket buffers while the server isn't reading, then the server receives everything the
+    // client managed to write, except a frame it's in the middle of. Frames which weren't started are put back.
+    let mut sent = 0u32;
+    let mut received = 0u32;
+    for _ in 0..8 {
+        loop {
+            assert!(sent < 1 << 20);
+            for _ in 0..256 {
+                let (channel, packet) = numbered(sent);
+                client[id].send_on(channel, packet).unwrap();
+                sent += 1;
+            }
+
+            assert!(client.process(Duration::from_millis(1)).unwrap().is_none());
+            if client[id].queued_packets() != 0 {
+                break;
+            }
+        }
+
+        let mut last = std::time::Instant::now();
+        while last.elapsed() < Duration::from_millis(50) {
+            if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+                let (channel, packet) = numbered(received);
+                assert_eq!(event.kind, EventKind::Receive { channel, packet });
+                received += 1;
+                last = std::time::Instant::now();
+            }
+        }
+    }
+
+    client[id]
+        .send_with(b"urgent".to_vec(), Priority::High)
+        .unwrap();
+
+    let drained = received;
+    let start = std::time::Instant::now();
+    let mut overtaken = None;
+    while received < sent || overtaken.is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(client.process(Duration::from_millis(0)).unwrap().is_none());
+        if let Some(event) = server.process(Duration::from_millis(1)).unwrap() {
+            match event.kind {
+                EventKind::Receive { ref packet, .. } if packet == b"urgent" => {
+                    overtaken = Some(received - drained);
+                }
+                kind => {
+                    let (channel, packet) = numbered(received);
+                    assert_eq!(kind, EventKind::Receive { channel, packet });
+                    received += 1;
+                }
+            }
+        }
+    }
+
+    assert!(overtaken <= Some(1), "{:?} packets went before", overtaken);
+}
+
+#[test]
+fn test_backpressure() {
+    // Never sends the hello, so that packets stay in the outgoing queue.
+    let _listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, PORT + 13)).unwrap();
+
+    let builder = Host::<()>::builder().high_water_mark(100);
+
+    let mut host = builder.clone().client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 13)).unwrap();
+    peer.send(vec![1; 60]).unwrap();
+    assert_eq!(peer.queued_packets(), 1);
+    assert!(peer.queued_bytes() > 60);
+    assert_eq!(peer.send(vec![2; 60]), Err(SendError::QueueFull));
+    assert_eq!(peer.queued_packets(), 1);
+
+    let mut host = builder
+        .clone()
+        .backpressure(Backpressure::DropOldest)
+        .client()
+        .unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 13)).unwrap();
+    for i in 0..3 {
+        peer.send(vec![i; 40]).unwrap();
+    }
+    assert_eq!(peer.queued_packets(), 2);
+
+    let mut host = builder
+        .backpressure(Backpressure::Disconnect)
+        .client()
+        .unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 13)).unwrap();
+    peer.send(vec![1; 60]).unwrap();
+    assert_eq!(peer.send(vec![2; 60]), Err(SendError::QueueFull));
+    assert_eq!(peer.send(vec![3; 1]), Err(SendError::Disconnected));
+
+    // Disconnected before the connection was established.
+    let event = host.process_blocking().unwrap();
+    assert_eq!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::QueueFull)
+    );
+}
+
+#[test]
+fn test_shared_broadcast() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder().server((Ipv4Addr::LOCALHOST, PORT + 14).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            for _ in 0..2 {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(event.kind, EventKind::Connect);
+            }
+
+            let packet: Arc<[u8]> = vec![7; 64 * 1024].into();
+            host.broadcast(packet.clone());
+            assert_eq!(Arc::strong_count(&packet), 3);
+
+            for _ in 0..2 {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+            }
+
+            assert_eq!(Arc::strong_count(&packet), 1);
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    for _ in 0..2 {
+        host.connect((Ipv4Addr::LOCALHOST, PORT + 14)).unwrap();
+    }
+
+    let mut received = 0;
+    while received < 2 {
+        let event = host.process_blocking().unwrap();
+        if let EventKind::Receive { packet, .. } = event.kind {
+            assert_eq!(packet, vec![7; 64 * 1024]);
+            event.peer.disconnect();
+            received += 1;
+        }
+    }
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_batched_writes() {
+    // Far more than fits into the socket buffers, so that batches are interrupted in the middle of a frame.
+    fn packet(i: usize) -> Vec<u8> {
+        vec![i as u8; i % 3000 + 1]
+    }
+
+    const PACKETS: usize = 2000;
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT + 15).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            for i in 0..PACKETS {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(
+                    event.kind,
+                    EventKind::Receive {
+                        channel: 0,
+                        packet: packet(i)
+                    }
+                );
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 15)).unwrap();
+    for i in 0..PACKETS {
+        peer.send(packet(i)).unwrap();
+    }
+
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_read_size() {
+    // Packets larger than the read size and the preallocation limit, mixed with small ones.
+    fn packets() -> Vec<Vec<u8>> {
+        vec![
+            b"small".to_vec(),
+            vec![1; 3 * 1024 * 1024],
+            b"after".to_vec(),
+            vec![2; 100],
+        ]
+    }
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .read_size(7)
+                .server((Ipv4Addr::LOCALHOST, PORT + 16).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            for packet in packets() {
+                let event = host.process_blocking().unwrap();
+                assert_eq!(event.kind, EventKind::Receive { channel: 0, packet });
+            }
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 16)).unwrap();
+    for packet in packets() {
+        peer.send(packet).unwrap();
+    }
+
+    peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_connect_refused() {
+    let mut host = Host::<()>::client().unwrap();
+
+    // Nobody listens on this port, no `Connect` event is generated.
+    host.connect((Ipv4Addr::LOCALHOST, PORT + 17)).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert!(matches!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::Refused(_))
+    ));
+}
+
+#[test]
+fn test_connect_timeout() {
+    // Never answers the connection attempt.
+    let _socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, PORT + 18)).unwrap();
+
+    let mut host = Host::<()>::builder()
+        .transport(Transport::Udp)
+        .connect_timeout(Duration::from_millis(200))
+        .client()
+        .unwrap();
+
+    host.connect((Ipv4Addr::LOCALHOST, PORT + 18)).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Timeout));
+}
+
+#[test]
+fn test_connect_fallback() {
+    let refused: SocketAddr = (Ipv4Addr::LOCALHOST, PORT + 19).into();
+    let working: SocketAddr = (Ipv4Addr::LOCALHOST, PORT + 20).into();
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::server(working);
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::client().unwrap();
+
+    // The failed attempt isn't reported.
+    host.connect(&[refused, working][..]).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+    assert_eq!(event.peer.addr(), working);
+    event.peer.disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+
+    // Only the last failure is reported.
+    host.connect(&[refused, refused][..]).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert!(matches!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::Refused(_))
+    ));
+
+    if let Some(event) = host.process(Duration::from_millis(100)).unwrap() {
+        panic!("unexpected event {:?}", event.kind);
+    }
+}
+
+/// Resolves `asnet.test` to the loopback after a delay, fails for any other name.
+struct StubResolver(Duration);
+
+impl Resolver for StubResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        thread::sleep(self.0);
+
+        match host {
+            "asnet.test" => Ok(vec![(Ipv4Addr::LOCALHOST, port).into()]),
+            _ => Err(Error::new(ErrorKind::NotFound, "unknown host")),
+        }
+    }
+}
+
+#[test]
+fn test_connect_host() {
+    let mut server = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT + 21).into()).unwrap();
+    let mut host = Host::<()>::builder()
+        .resolver(StubResolver(Duration::from_millis(200)))
+        .client()
+        .unwrap();
+
+    let peer = host.connect_host("asnet.test", PORT + 21).unwrap();
+    assert!(peer.resolving());
+    peer.send(b"queued".to_vec()).unwrap();
+
+    // The lookup doesn't block the host.
+    let start = std::time::Instant::now();
+    assert!(host.process(Duration::from_millis(50)).unwrap().is_none());
+    assert!(start.elapsed() < Duration::from_millis(150));
+
+    // The connection is established once the server answers the hello.
+    let event = loop {
+        server.process(Duration::from_millis(10)).unwrap();
+        if let Some(event) = host.process(Duration::from_millis(10)).unwrap() {
+            break event;
+        }
+    };
+    assert_eq!(event.kind, EventKind::Connect);
+    assert!(!event.peer.resolving());
+    assert_eq!(event.peer.addr(), (Ipv4Addr::LOCALHOST, PORT + 21).into());
+
+    host.connect_host("unknown.test", PORT + 21).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::Unreachable(ErrorKind::NotFound.into()))
+    );
+}
+
+#[test]
+fn test_heartbeat() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .timeout(Duration::from_millis(300))
+                .heartbeat_interval(Duration::from_millis(50))
+                .server((Ipv4Addr::LOCALHOST, PORT + 22).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            // Idle for much longer than the timeout, the pings keep the connection alive.
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+        })
+    };
+
+    barrier.wait();
+
+    let mut host = Host::<()>::builder()
+        .timeout(Duration::from_millis(300))
+        .heartbeat_interval(Duration::from_millis(50))
+        .client()
+        .unwrap();
+    let id = host.connect((Ipv4Addr::LOCALHOST, PORT + 22)).unwrap().id();
+    assert!(host[id].rtt().is_none());
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let start = std::time::Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        if let Some(event) = host.process(Duration::from_millis(100)).unwrap() {
+            panic!("unexpected event {:?}", event.kind);
+        }
+    }
+
+    assert!(host[id]
+        .rtt()
+        .is_some_and(|rtt| rtt < Duration::from_millis(50)));
+    assert!(host[id].rtt_variance().is_some());
+
+    host[id].disconnect_later();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_unanswered_heartbeat() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::builder()
+                .timeout(Duration::from_millis(300))
+                .heartbeat_interval(Duration::from_millis(50))
+                .server((Ipv4Addr::LOCALHOST, PORT + 23).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            // Data keeps coming, but the pings are never answered.
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Timeout));
+        })
+    };
+
+    barrier.wait();
+
+    // Sends a hello and then messages the server ignores, without ever reading.
+    let mut stream = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, PORT + 23)).unwrap();
+    stream.write_all(b"\xff\x00\x00\x00\x02\x00\x01").unwrap();
+    while !handle.is_finished() {
+        let _ = stream.write_all(b"\xff\x00\x00\x00\x01\x7f");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_busy_heartbeat() {
+    let builder = Host::<()>::builder()
+        .timeout(Duration::from_secs(2))
+        .heartbeat_interval(Duration::from_millis(200));
+    let mut server = builder
+        .clone()
+        .server((Ipv4Addr::LOCALHOST, PORT + 37).into())
+        .unwrap();
+    let mut client = builder.client().unwrap();
+    let client_id = client
+        .connect((Ipv4Addr::LOCALHOST, PORT + 37))
+        .unwrap()
+        .id();
+
+    let mut server_id = None;
+    while server_id.is_none() {
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            assert_eq!(event.kind, EventKind::Connect);
+            server_id = Some(event.peer.id());
+        }
+        client.process(Duration::from_millis(10)).unwrap();
+    }
+    let server_id = server_id.unwrap();
+
+    // The client keeps sending, so the server has no reason to ping it.
+    let start = std::time::Instant::now();
+    while start.elapsed() < Duration::from_millis(600) {
+        client[client_id].send(b"busy".to_vec()).unwrap();
+        client.process(Duration::from_millis(5)).unwrap();
+        server.process(Duration::from_millis(5)).unwrap();
+    }
+    assert!(server[server_id].rtt().is_none());
+
+    // Once the client is quiet, it's pinged.
+    let start = std::time::Instant::now();
+    while start.elapsed() < Duration::from_millis(600) {
+        client.process(Duration::from_millis(5)).unwrap();
+        server.process(Duration::from_millis(5)).unwrap();
+    }
+    assert!(server[server_id].rtt().is_some());
+}
+
+#[test]
+fn test_reconnect() {
+    let barrier = Arc::new(Barrier::new(2));
+    let handle = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let host = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT + 24).into());
+
+            barrier.wait();
+
+            let mut host = host.unwrap();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+            event.peer.disconnect();
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Local));
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(event.kind, EventKind::Connect);
+
+            let event = host.process_blocking().unwrap();
+            assert_eq!(
+                event.kind,
+                EventKind::Receive {
+                    channel: 0,
+                    packet: b"queued".to_vec()
+                }
+            );
+        })
+    };
+
+    barrier.wait();
+
+    let policy = ReconnectPolicy::new()
+        .initial_delay(Duration::from_millis(50))
+        .max_attempts(2)
+        .jitter(0.0);
+
+    let mut host = Host::<u32>::client().unwrap();
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 24)).unwrap();
+    peer.set_reconnect_policy(Some(policy));
+    *peer.data_mut() = 42;
+    let id = peer.id();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    // The peer keeps its handle and its data.
+    let event = host.process_blocking().unwrap();
+    assert!(matches!(event.kind, EventKind::Reconnecting(_)));
+    assert_eq!(event.peer.id(), id);
+    assert_eq!(*event.peer.data(), 42);
+    assert!(event.peer.reconnecting());
+    event.peer.send(b"queued".to_vec()).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Reconnected);
+    assert_eq!(event.peer.id(), id);
+
+    // The queued packet is sent once the channels are negotiated, so keep processing until the server is gone.
+    // Then the peer gives up after the configured number of attempts.
+    let event = host.process_blocking().unwrap();
+    assert!(matches!(event.kind, EventKind::Reconnecting(_)));
+
+    handle.join().unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert!(matches!(
+        event.kind,
+        EventKind::Disconnect(DisconnectReason::Refused(_))
+    ));
+    assert_eq!(event.peer.id(), id);
+}
+
+#[test]
+fn test_admission() {
+    let banned = Arc::new(AtomicBool::new(false));
+    let mut server = Host::<()>::builder()
+        .max_peers_per_ip(2)
+        .accept_filter({
+            let banned = banned.clone();
+            move |_| !banned.load(Ordering::SeqCst)
+        })
+        .server((Ipv4Addr::LOCALHOST, PORT + 25).into())
+        .unwrap();
+
+    let mut client = Host::<()>::client().unwrap();
+    for _ in 0..3 {
+        client.connect((Ipv4Addr::LOCALHOST, PORT + 25)).unwrap();
+    }
+
+    // Runs both hosts until `done` returns true, counting the events of the server and the disconnects of the client.
+    let mut connected = Vec::new();
+    let mut rejected = 0;
+    let mut run =
+        |server: &mut Host<()>, client: &mut Host<()>, done: &dyn Fn(usize, usize) -> bool| {
+            let start = std::time::Instant::now();
+            while !done(connected.len(), rejected) {
+                assert!(start.elapsed() < Duration::from_secs(5));
+
+                if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+                    match event.kind {
+                        EventKind::Connect => connected.push(event.peer.id()),
+                        kind => panic!("unexpected event {:?}", kind),
+                    }
+                }
+
+                if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+                    if let EventKind::Disconnect(_) = event.kind {
+                        rejected += 1;
+                    }
+                }
+            }
+        };
+
+    // The third connection from the same address is over the limit.
+    run(&mut server, &mut client, &|connected, rejected| {
+        connected == 2 && rejected == 1
+    });
+
+    // Banned addresses are rejected even within the limit.
+    banned.store(true, Ordering::SeqCst);
+    client.connect((Ipv4Addr::LOCALHOST, PORT + 25)).unwrap();
+    run(&mut server, &mut client, &|connected, rejected| {
+        connected == 2 && rejected == 2
+    });
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut server = Host::<()>::builder()
+        .rate_limit(RateLimit::new().packets(5).burst(Duration::from_secs(1)))
+        .rate_limit_action(RateLimitAction::Disconnect)
+        .server((Ipv4Addr::LOCALHOST, PORT + 26).into())
+        .unwrap();
+
+    let mut client = Host::<()>::client().unwrap();
+    let peer = client.connect((Ipv4Addr::LOCALHOST, PORT + 26)).unwrap();
+    for i in 0..20 {
+        peer.send(vec![i]).unwrap();
+    }
+
+    let start = std::time::Instant::now();
+    let mut received = 0;
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        client.process(Duration::from_millis(10)).unwrap();
+        let event = match server.process(Duration::from_millis(10)).unwrap() {
+            Some(event) => event,
+            None => continue,
+        };
+
+        match event.kind {
+            EventKind::Connect => {}
+            EventKind::Receive { .. } => received += 1,
+            EventKind::Disconnect(reason) => {
+                assert_eq!(reason, DisconnectReason::RateLimited);
+                break;
+            }
+            kind => panic!("unexpected event {:?}", kind),
+        }
+    }
+
+    // Only the burst got through.
+    assert_eq!(received, 5);
+}
+
+#[test]
+fn test_rate_limit_pause() {
+    const PACKETS: u8 = 10;
+
+    let mut server = Host::<()>::builder()
+        .rate_limit_per_ip(
+            RateLimit::new()
+                .packets(20)
+                .burst(Duration::from_millis(100)),
+        )
+        .rate_limit_action(RateLimitAction::Pause(Duration::from_millis(50)))
+        .server((Ipv4Addr::LOCALHOST, PORT + 27).into())
+        .unwrap();
+
+    let mut client = Host::<()>::client().unwrap();
+    let peer = client.connect((Ipv4Addr::LOCALHOST, PORT + 27)).unwrap();
+    for i in 0..PACKETS {
+        peer.send(vec![i]).unwrap();
+    }
+
+    let start = std::time::Instant::now();
+    let mut received = Vec::new();
+    while received.len() < PACKETS as usize {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        client.process(Duration::from_millis(10)).unwrap();
+        let event = match server.process(Duration::from_millis(10)).unwrap() {
+            Some(event) => event,
+            None => continue,
+        };
+
+        match event.kind {
+            EventKind::Connect => {}
+            EventKind::Receive { packet, .. } => received.extend(packet),
+            kind => panic!("unexpected event {:?}", kind),
+        }
+    }
+
+    // Nothing is lost, the packets beyond the burst of 2 are delivered at 20 per second.
+    assert_eq!(received, (0..PACKETS).collect::<Vec<_>>());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+/// Creates TLS configurations of a server with a self-signed certificate for `localhost` and of a client trusting it.
+#[cfg(feature = "tls")]
+fn tls_configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let server = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls() {
+    // Large enough to take many TLS records and writes.
+    let large = (0..1 << 20).map(|i| i as u8).collect::<Vec<_>>();
+
+    let (server_config, client_config) = tls_configs();
+    let mut server = Host::<()>::builder()
+        .tls_server(server_config)
+        .server((Ipv4Addr::LOCALHOST, PORT + 28).into())
+        .unwrap();
+
+    let mut client = Host::<()>::builder()
+        .tls_client(client_config)
+        .client()
+        .unwrap();
+
+    let id = client
+        .connect_tls((Ipv4Addr::LOCALHOST, PORT + 28), "localhost")
+        .unwrap()
+        .id();
+    client[id].send(b"hello".to_vec()).unwrap();
+    client[id].send(large.clone()).unwrap();
+
+    let start = std::time::Instant::now();
+    let mut server_events = Vec::new();
+    let mut client_events = Vec::new();
+    while server_events.len() < 3 || client_events.len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+            client_events.push(event.kind);
+        }
+
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            if let EventKind::Receive { ref packet, .. } = event.kind {
+                if packet == b"hello" {
+                    event.peer.send(b"world".to_vec()).unwrap();
+                }
+            }
+
+            server_events.push(event.kind);
+        }
+    }
+
+    assert_eq!(
+        server_events,
+        vec![
+            EventKind::Connect,
+            EventKind::Receive {
+                channel: 0,
+                packet: b"hello".to_vec()
+            },
+            EventKind::Receive {
+                channel: 0,
+                packet: large
+            },
+        ]
+    );
+
+    assert_eq!(
+        client_events,
+        vec![
+            EventKind::Connect,
+            EventKind::Receive {
+                channel: 0,
+                packet: b"world".to_vec()
+            },
+        ]
+    );
+
+    // The session is closed before the connection.
+    client[id].disconnect_later();
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        client.process(Duration::from_millis(10)).unwrap();
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            assert_eq!(event.kind, EventKind::Disconnect(DisconnectReason::Closed));
+            break;
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls_server_name() {
+    let (server_config, client_config) = tls_configs();
+    let mut server = Host::<()>::builder()
+        .tls_server(server_config)
+        .server((Ipv4Addr::LOCALHOST, PORT + 29).into())
+        .unwrap();
+
+    let mut client = Host::<()>::builder()
+        .tls_client(client_config)
+        .client()
+        .unwrap();
+
+    // The certificate isn't valid for this name.
+    client
+        .connect_tls((Ipv4Addr::LOCALHOST, PORT + 29), "example.com")
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let mut server_events = Vec::new();
+    let reason = loop {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            server_events.push(event.kind);
+        }
+
+        if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+            match event.kind {
+                EventKind::Disconnect(reason) => break reason,
+                kind => panic!("unexpected event {:?}", kind),
+            }
+        }
+    };
+
+    assert!(matches!(reason, DisconnectReason::InvalidData(_)));
+
+    // The server never considered the peer connected.
+    assert!(!server_events.contains(&EventKind::Connect));
+}
+
+#[test]
+fn test_auth() {
+    let mut server = Host::<String>::builder()
+        .authenticator(|_, token| match token {
+            b"secret" => Some("alice".to_owned()),
+            _ => None,
+        })
+        .server((Ipv4Addr::LOCALHOST, PORT + 30).into())
+        .unwrap();
+
+    let mut client = Host::<()>::builder()
+        .auth_token(&b"secret"[..])
+        .client()
+        .unwrap();
+    let mut intruder = Host::<()>::builder()
+        .auth_token(&b"guess"[..])
+        .client()
+        .unwrap();
+
+    let peer = client.connect((Ipv4Addr::LOCALHOST, PORT + 30)).unwrap();
+    intruder.connect((Ipv4Addr::LOCALHOST, PORT + 30)).unwrap();
+
+    // Packets sent before the token is accepted are held back.
+    peer.send(b"hello".to_vec()).unwrap();
+
+    let start = std::time::Instant::now();
+    let (mut connected, mut received, mut rejected) = (false, false, 0);
+    while !(connected && received && rejected == 2) {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+            match event.kind {
+                EventKind::Connect => connected = true,
+                kind => panic!("unexpected event {:?}", kind),
+            }
+        }
+
+        if let Some(event) = intruder.process(Duration::from_millis(10)).unwrap() {
+            assert_eq!(
+                event.kind,
+                EventKind::Disconnect(DisconnectReason::AuthFailed)
+            );
+            rejected += 1;
+        }
+
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            match event.kind {
+                EventKind::Connect => assert_eq!(event.peer.data(), "alice"),
+                EventKind::Receive { ref packet, .. } => {
+                    assert_eq!(&packet[..], b"hello");
+                    received = true;
+                }
+                EventKind::Disconnect(DisconnectReason::AuthFailed) => rejected += 1,
+                kind => panic!("unexpected event {:?}", kind),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_negotiation() {
+    let mut server = Host::<()>::builder()
+        .application_id(7)
+        .protocol_version(3)
+        .min_protocol_version(2)
+        .features(0b101)
+        .server((Ipv4Addr::LOCALHOST, PORT + 31).into())
+        .unwrap();
+
+    // The first client is compatible, the others run an older version and another application.
+    let mut clients = vec![
+        Host::<()>::builder()
+            .application_id(7)
+            .protocol_version(2)
+            .features(0b110)
+            .client()
+            .unwrap(),
+        Host::<()>::builder()
+            .application_id(7)
+            .protocol_version(1)
+            .client()
+            .unwrap(),
+        Host::<()>::builder().application_id(8).client().unwrap(),
+    ];
+
+    for client in &mut clients {
+        client.connect((Ipv4Addr::LOCALHOST, PORT + 31)).unwrap();
+    }
+
+    let expected = [
+        EventKind::Connect,
+        EventKind::Disconnect(DisconnectReason::IncompatibleVersion(3)),
+        EventKind::Disconnect(DisconnectReason::WrongApplication(7)),
+    ];
+
+    let start = std::time::Instant::now();
+    let mut done = [false; 3];
+    let mut server_events = Vec::new();
+    while done.contains(&false) || server_events.len() < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        for (i, client) in clients.iter_mut().enumerate() {
+            if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+                assert!(!done[i]);
+                assert_eq!(event.kind, expected[i]);
+                if event.kind == EventKind::Connect {
+                    assert_eq!(event.peer.protocol_version(), Some(2));
+                    assert_eq!(event.peer.features(), 0b100);
+                }
+
+                done[i] = true;
+            }
+        }
+
+        if let Some(event) = server.process(Duration::from_millis(10)).unwrap() {
+            if event.kind == EventKind::Connect {
+                assert_eq!(event.peer.protocol_version(), Some(2));
+                assert_eq!(event.peer.features(), 0b100);
+            }
+
+            server_events.push(event.kind);
+        }
+    }
+
+    assert!(server_events.contains(&EventKind::Connect));
+    assert!(server_events.contains(&EventKind::Disconnect(
+        DisconnectReason::IncompatibleVersion(1)
+    )));
+    assert!(
+        server_events.contains(&EventKind::Disconnect(DisconnectReason::WrongApplication(
+            8
+        )))
+    );
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_compression() {
+    let algorithms = [
+        #[cfg(feature = "lz4")]
+        Compression::Lz4,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(3),
+    ];
+
+    for compression in algorithms.iter().cloned() {
+        let mut server = Host::<()>::builder()
+            .max_packet_size(64 * 1024)
+            .server((Ipv4Addr::LOCALHOST, PORT + 32).into())
+            .unwrap();
+
+        let mut client = Host::<()>::builder()
+            .compression(compression)
+            .client()
+            .unwrap();
+        client.connect((Ipv4Addr::LOCALHOST, PORT + 32)).unwrap();
+
+        let event = loop {
+            server.process(Duration::from_millis(10)).unwrap();
+            if let Some(event) = client.process(Duration::from_millis(10)).unwrap() {
+                break event;
+            }
+        };
+        assert_eq!(event.kind, EventKind::Connect);
+        assert_eq!(event.peer.compression(), Some(compression));
+
+        // A compressible snapshot and a packet below the threshold.
+        let snapshot = b"snapshot".repeat(4096);
+        let peer = event.peer.id();
+        event.peer.send(snapshot.clone()).unwrap();
+        event.peer.send(b"small".to_vec()).unwrap();
+
+        let start = std::time::Instant::now();
+        let mut received = Vec::new();
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            client.process(Duration::from_millis(10)).unwrap();
+            let event = match server.process(Duration::from_millis(10)).unwrap() {
+                Some(event) => event,
+                None => continue,
+            };
+
+            match event.kind {
+                EventKind::Connect => assert_eq!(event.peer.compression(), None),
+                EventKind::Receive { packet, .. } => {
+                    received.push(packet);
+
+                    // Then one decompressing over the limit of the server.
+                    if received.len() == 2 {
+                        client[peer].send(vec![0; 1024 * 1024]).unwrap();
+                    }
+                }
+                EventKind::Disconnect(reason) => {
+                    assert!(
+                        matches!(reason, DisconnectReason::InvalidData(_)),
+                        "{:?}",
+                        reason
+                    );
+                    break;
+                }
+                kind => panic!("unexpected event {:?}", kind),
+            }
+        }
+
+        assert_eq!(received, vec![snapshot, b"small".to_vec()]);
+    }
+}
+
+#[cfg(any(feature = "bincode", feature = "json", feature = "msgpack"))]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum Message {
+    Join { name: String },
+    Move(i32, i32),
+}
+
+#[cfg(any(feature = "bincode", feature = "json", feature = "msgpack"))]
+#[test]
+fn test_messages() {
+    /// `oversized` is a `Join` message whose name is announced much longer than the packet.
+    fn run(format: impl Format + Copy + 'static, oversized: &[u8]) {
+        let mut server = Host::<()>::builder()
+            .format(format)
+            .decode_error_action(DecodeErrorAction::Event)
+            .server((Ipv4Addr::LOCALHOST, PORT + 33).into())
+            .unwrap();
+
+        let mut client = Host::<()>::builder().format(format).client().unwrap();
+        let peer = client.connect((Ipv4Addr::LOCALHOST, PORT + 33)).unwrap();
+        peer.send_msg(&Message::Join {
+            name: "alice".to_owned(),
+        })
+        .unwrap();
+        peer.send(b"garbage".to_vec()).unwrap();
+        // A message followed by a byte that doesn't belong to it.
+        let mut trailing = format.serialize(&Message::Move(3, 4)).unwrap();
+        trailing.push(b'x');
+        peer.send(trailing).unwrap();
+        peer.send(oversized.to_vec()).unwrap();
+        peer.send_msg(&Message::Move(1, -2)).unwrap();
+
+        let expected = [
+            MessageKind::Connect,
+            MessageKind::Receive {
+                channel: 0,
+                message: Message::Join {
+                    name: "alice".to_owned(),
+                },
+            },
+            MessageKind::DecodeError {
+                channel: 0,
+                error: ErrorKind::InvalidData.into(),
+            },
+            MessageKind::DecodeError {
+                channel: 0,
+                error: ErrorKind::InvalidData.into(),
+            },
+            MessageKind::DecodeError {
+                channel: 0,
+                error: ErrorKind::InvalidData.into(),
+            },
+            MessageKind::Receive {
+                channel: 0,
+                message: Message::Move(1, -2),
+            },
+        ];
+
+        let start = std::time::Instant::now();
+        let mut received = 0;
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            if let Some(event) = server.process_messages(Duration::from_millis(10)).unwrap() {
+                assert_eq!(event.kind, expected[received]);
+                received += 1;
+
+                // Packets which aren't messages disconnect by default, the messages after them are dropped.
+                if received == expected.len() {
+                    event.peer.send(b"garbage".to_vec()).unwrap();
+                    event.peer.send_msg(&Message::Move(5, 6)).unwrap();
+                }
+            }
+
+            if let Some(event) = client
+                .process_messages::<Message>(Duration::from_millis(10))
+                .unwrap()
+            {
+                match event.kind {
+                    MessageKind::Connect => {}
+                    MessageKind::Disconnect(reason) => {
+                        assert!(matches!(reason, DisconnectReason::InvalidData(_)));
+                        break;
+                    }
+                    kind => panic!("unexpected event {:?}", kind),
+                }
+            }
+        }
+
+        assert_eq!(received, expected.len());
+    }
+
+    // The variant and a name of 2^40 bytes.
+    #[cfg(feature = "bincode")]
+    run(Bincode, b"\x00\xfd\x00\x00\x00\x00\x00\x01\x00\x00");
+    #[cfg(feature = "json")]
+    run(Json, br#"{"Join":{"name":"al"#);
+    // A map of the variant to a map of the name, which is a string of 2^32 - 1 bytes.
+    #[cfg(feature = "msgpack")]
+    run(MessagePack, b"\x81\xa4Join\x81\xa4name\xdb\xff\xff\xff\xff");
+
+    // Messages need a format.
+    let mut host = Host::<()>::client().unwrap();
+    let err = host
+        .process_messages::<Message>(Duration::from_millis(0))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let peer = host.connect((Ipv4Addr::LOCALHOST, PORT + 33)).unwrap();
+    assert_eq!(
+        peer.send_msg(&Message::Move(1, 2)),
+        Err(SendError::NoFormat)
+    );
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_corrupt_compression() {
+    let mut host = Host::<()>::server((Ipv4Addr::LOCALHOST, PORT + 34).into()).unwrap();
+
+    // A frame compressed with Zstandard, whose header is valid but whose body was cut off.
+    let mut compressed = zstd::bulk::compress(&b"snapshot".repeat(512), 3).unwrap();
+    compressed.truncate(compressed.len() / 2);
+
+    let mut frame = vec![0xfe];
+    frame.extend_from_slice(&(compressed.len() as u32 + 2).to_be_bytes());
+    // The id of Zstandard and channel 0.
+    frame.extend_from_slice(&[1, 0]);
+    frame.extend_from_slice(&compressed);
+
+    let mut stream = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, PORT + 34)).unwrap();
+    stream.write_all(b"\xff\x00\x00\x00\x02\x00\x01").unwrap();
+    stream.write_all(&frame).unwrap();
+
+    let event = host.process_blocking().unwrap();
+    assert_eq!(event.kind, EventKind::Connect);
+
+    let event = host.process_blocking().unwrap();
+    assert!(
+        matches!(
+            event.kind,
+            EventKind::Disconnect(DisconnectReason::InvalidData(_))
+        ),
+        "{:?}",
+        event.kind
+    );
+
+    // Only the sender is affected, the host keeps running.
+    assert!(host.process(Duration::from_millis(10)).unwrap().is_none());
 }
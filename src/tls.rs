@@ -0,0 +1,215 @@
+//! TLS on top of a TCP stream, driven by the readiness of the stream like the plain connection.
+use iovec::IoVec;
+use mio::net::TcpStream;
+use mio::Ready;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, IoSlice, Read, Write};
+use std::sync::Arc;
+
+/// The TLS configuration of a `Host`.
+#[derive(Clone, Default)]
+pub(crate) struct TlsConfig {
+    pub(crate) server: Option<Arc<ServerConfig>>,
+    pub(crate) client: Option<Arc<ClientConfig>>,
+}
+
+/// What a client needs to start a TLS session with the remote side, kept for every connection attempt.
+#[derive(Clone)]
+pub(crate) struct TlsConnect {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: ServerName<'static>,
+}
+
+impl TlsConnect {
+    pub(crate) fn new(config: Arc<ClientConfig>, server_name: &str) -> Result<TlsConnect, Error> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+        Ok(TlsConnect {
+            config,
+            server_name,
+        })
+    }
+}
+
+/// A TLS session of a TCP stream.
+pub(crate) struct TlsSession {
+    connection: Connection,
+    /// Decrypted data, or the end of it, is waiting to be read regardless of the readiness of the stream.
+    buffered: bool,
+}
+
+impl TlsSession {
+    pub(crate) fn client(connect: &TlsConnect) -> Result<TlsSession, Error> {
+        let connection = ClientConnection::new(connect.config.clone(), connect.server_name.clone())
+            .map_err(invalid_data)?;
+
+        Ok(TlsSession::new(connection.into()))
+    }
+
+    pub(crate) fn server(config: Arc<ServerConfig>) -> Result<TlsSession, Error> {
+        let connection = ServerConnection::new(config).map_err(invalid_data)?;
+        Ok(TlsSession::new(connection.into()))
+    }
+
+    fn new(connection: Connection) -> TlsSession {
+        TlsSession {
+            connection,
+            buffered: false,
+        }
+    }
+
+    pub(crate) fn handshaking(&self) -> bool {
+        self.connection.is_handshaking()
+    }
+
+    /// Returns true if decrypted data can be read without reading the stream.
+    pub(crate) fn buffered(&self) -> bool {
+        self.buffered
+    }
+
+    /// Returns true if encrypted data is waiting to be written to the stream.
+    pub(crate) fn wants_write(&self) -> bool {
+        self.connection.wants_write()
+    }
+
+    /// Exchanges handshake messages as far as the readiness of the stream allows.
+    ///
+    /// Returns the number of bytes transferred. Readiness is removed once the stream would block.
+    pub(crate) fn handshake(
+        &mut self,
+        stream: &mut TcpStream,
+        ready: &mut Ready,
+    ) -> Result<usize, Error> {
+        let mut processed = 0;
+        loop {
+            let mut progress = 0;
+
+            if self.connection.wants_write() && ready.is_writable() {
+                match self.connection.write_tls(stream) {
+                    Ok(n) => progress += n,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                        ready.remove(Ready::writable())
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if self.connection.is_handshaking()
+                && self.connection.wants_read()
+                && ready.is_readable()
+            {
+                match self.connection.read_tls(stream) {
+                    Ok(0) => return Err(ErrorKind::ConnectionReset.into()),
+                    Ok(n) => {
+                        progress += n;
+                        self.process_packets(stream)?;
+                    }
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                        ready.remove(Ready::readable())
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            processed += progress;
+            if progress == 0 || (!self.connection.is_handshaking() && !self.wants_write()) {
+                return Ok(processed);
+            }
+        }
+    }
+
+    /// Reads decrypted data, reading the stream as needed. Returns 0 once the remote side has closed the session.
+    pub(crate) fn read(&mut self, stream: &mut TcpStream, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // The stream was closed without the session.
+                Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => self.buffered = false,
+                Err(err) => return Err(err),
+            }
+
+            if self.connection.read_tls(stream)? != 0 {
+                self.process_packets(stream)?;
+            }
+        }
+    }
+
+    /// Encrypts as much of the data as possible and writes it to the stream. Returns the number of bytes taken.
+    ///
+    /// Fails with `ErrorKind::WouldBlock` only if nothing could be taken.
+    pub(crate) fn write_bufs(
+        &mut self,
+        stream: &mut TcpStream,
+        bufs: &[&IoVec],
+    ) -> Result<usize, Error> {
+        let bufs = bufs.iter().map(|buf| IoSlice::new(buf)).collect::<Vec<_>>();
+
+        loop {
+            let n = self.connection.writer().write_vectored(&bufs)?;
+            if n != 0 {
+                // Whatever doesn't fit into the stream now is written once it's writable again.
+                if let Err(err) = self.flush(stream) {
+                    if err.kind() != ErrorKind::WouldBlock {
+                        return Err(err);
+                    }
+                }
+
+                return Ok(n);
+            }
+
+            if !self.wants_write() {
+                return Ok(0);
+            }
+
+            // The session won't take more data until the encrypted data is written.
+            self.flush(stream)?;
+        }
+    }
+
+    /// Writes the encrypted data waiting to be sent. Returns the number of bytes written.
+    pub(crate) fn flush(&mut self, stream: &mut TcpStream) -> Result<usize, Error> {
+        let mut written = 0;
+        while self.wants_write() {
+            match self.connection.write_tls(stream)? {
+                // The stream doesn't take any more data, like a reset connection.
+                0 => return Err(ErrorKind::ConnectionReset.into()),
+                n => written += n,
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Tells the remote side that no more data will be sent.
+    ///
+    /// The notification is written as far as the stream allows it right away.
+    pub(crate) fn close(&mut self, stream: &mut TcpStream) -> Result<(), Error> {
+        self.connection.send_close_notify();
+        match self.flush(stream) {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn process_packets(&mut self, stream: &mut TcpStream) -> Result<(), Error> {
+        match self.connection.process_new_packets() {
+            Ok(state) => {
+                self.buffered = state.plaintext_bytes_to_read() != 0 || state.peer_has_closed();
+                Ok(())
+            }
+            Err(err) => {
+                // Let the remote side know what went wrong.
+                let _ = self.flush(stream);
+                Err(invalid_data(err))
+            }
+        }
+    }
+}
+
+fn invalid_data(err: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,533 @@
+//! A connection over UDP with a small ENet-like protocol on top.
+//!
+//! Every datagram starts with a header consisting of its kind and the session identifier chosen by the client,
+//! which allows datagrams belonging to a previous connection from the same address to be ignored.
+//! Reliable and sequenced datagrams carry a sequence number after the header, acknowledgements carry the
+//! sequence number of the reliable datagram they acknowledge.
+use super::channel::{OutgoingQueue, Queued};
+use super::event::DisconnectReason;
+use super::peer::{Delivery, ProcessError};
+use mio::net::UdpSocket;
+use mio::Ready;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The maximum size of a UDP datagram payload.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65507;
+/// The maximum size of a payload that fits into a single datagram.
+pub(crate) const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE - SEQUENCE_SIZE;
+
+const CONNECT: u8 = 0;
+const ACCEPT: u8 = 1;
+const DISCONNECT: u8 = 2;
+const RELIABLE: u8 = 3;
+const SEQUENCED: u8 = 4;
+const UNRELIABLE: u8 = 5;
+const ACK: u8 = 6;
+
+/// The size of the kind and session identifier.
+const HEADER_SIZE: usize = 5;
+/// The size of a sequence number.
+const SEQUENCE_SIZE: usize = 4;
+
+/// The maximum number of unacknowledged reliable packets. The receiver buffers at most this many packets
+/// received out of order.
+const WINDOW: usize = 256;
+
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(2);
+
+pub(crate) struct UdpConnection {
+    socket: Arc<UdpSocket>,
+    /// Whether the socket is connected to the remote side and used only by this connection.
+    owned: bool,
+    readable: bool,
+    addr: SocketAddr,
+    session: u32,
+    state: UdpState,
+    next_reliable: u32,
+    next_sequenced: u32,
+    unacked: VecDeque<Unacked>,
+    expected_reliable: u32,
+    out_of_order: HashMap<u32, Vec<u8>>,
+    last_sequenced: Option<u32>,
+    received: VecDeque<Vec<u8>>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    active: bool,
+    /// Received datagrams are discarded.
+    read_paused: bool,
+    /// Reused to assemble outgoing datagrams.
+    buffer: Vec<u8>,
+    /// Reused to receive datagrams on an owned socket, allocated the first time it's readable.
+    read_buffer: Vec<u8>,
+}
+
+impl UdpConnection {
+    /// Creates a connection over a socket connected to the remote side, which will initiate the handshake.
+    pub(crate) fn connect(socket: UdpSocket, addr: SocketAddr, now: Instant) -> UdpConnection {
+        let session = RandomState::new().build_hasher().finish() as u32;
+        UdpConnection::new(
+            Arc::new(socket),
+            true,
+            addr,
+            session,
+            UdpState::Connecting(now, 0),
+        )
+    }
+
+    /// Creates a connection over a shared server socket in response to a connect request.
+    pub(crate) fn accept(
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+        session: u32,
+    ) -> Result<UdpConnection, Error> {
+        let connection = UdpConnection::new(socket, false, addr, session, UdpState::Connected);
+        connection.send_control(ACCEPT)?;
+
+        Ok(connection)
+    }
+
+    fn new(
+        socket: Arc<UdpSocket>,
+        owned: bool,
+        addr: SocketAddr,
+        session: u32,
+        state: UdpState,
+    ) -> UdpConnection {
+        UdpConnection {
+            socket,
+            owned,
+            readable: false,
+            addr,
+            session,
+            state,
+            next_reliable: 0,
+            next_sequenced: 0,
+            unacked: VecDeque::new(),
+            expected_reliable: 0,
+            out_of_order: HashMap::new(),
+            last_sequenced: None,
+            received: VecDeque::new(),
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            active: false,
+            read_paused: false,
+            buffer: Vec::new(),
+            read_buffer: Vec::new(),
+        }
+    }
+
+    pub(crate) fn update_ready(&mut self, ready: Ready) {
+        if ready.is_readable() {
+            self.readable = true;
+        }
+    }
+
+    /// Handles a datagram received from the remote side.
+    pub(crate) fn handle_datagram(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+        max_frame_size: usize,
+    ) -> Result<(), ProcessError> {
+        if self.read_paused || datagram.len() < HEADER_SIZE || session(datagram) != self.session {
+            return Ok(());
+        }
+
+        let kind = datagram[0];
+        let body = &datagram[HEADER_SIZE..];
+
+        // Any datagram of the session proves that the remote side has accepted the connection.
+        if let UdpState::Connecting(..) = self.state {
+            self.state = UdpState::Connected;
+        }
+
+        if !matches!(self.state, UdpState::Connected) {
+            return Ok(());
+        }
+
+        self.active = true;
+
+        match kind {
+            CONNECT if !self.owned => {
+                // The acceptance got lost.
+                self.send_control(ACCEPT)?;
+            }
+            DISCONNECT => {
+                self.state = UdpState::RemoteClosed;
+            }
+            UNRELIABLE => {
+                check_size(body, max_frame_size)?;
+                self.received.push_back(body.to_vec());
+            }
+            RELIABLE | SEQUENCED | ACK if body.len() >= SEQUENCE_SIZE => {
+                let sequence = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let payload = &body[SEQUENCE_SIZE..];
+
+                match kind {
+                    RELIABLE => {
+                        check_size(payload, max_frame_size)?;
+                        self.handle_reliable(sequence, payload)?;
+                    }
+                    SEQUENCED => {
+                        check_size(payload, max_frame_size)?;
+
+                        let newer = self.last_sequenced.is_none_or(|last| {
+                            let distance = sequence.wrapping_sub(last);
+                            distance != 0 && distance < u32::MAX / 2
+                        });
+
+                        if newer {
+                            self.last_sequenced = Some(sequence);
+                            self.received.push_back(payload.to_vec());
+                        }
+                    }
+                    _ => self.handle_ack(sequence, now),
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Receives datagrams from an owned socket until it would block.
+    fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        now: Instant,
+        max_frame_size: usize,
+    ) -> Result<(), ProcessError> {
+        loop {
+            let n = match self.socket.recv(buffer) {
+                Ok(n) => n,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.readable = false;
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            self.handle_datagram(&buffer[..n], now, max_frame_size)?;
+        }
+    }
+
+    /// Receives datagrams, retransmits lost packets and sends ones from the outgoing queue. Returns whether anything was received.
+    pub(crate) fn process(
+        &mut self,
+        now: Instant,
+        max_frame_size: usize,
+        outgoing: &mut OutgoingQueue,
+        frames: &mut VecDeque<Vec<u8>>,
+    ) -> Result<bool, ProcessError> {
+        if self.owned && self.readable {
+            // Taken out while datagrams are handled, which needs the whole connection.
+            let mut buffer = std::mem::take(&mut self.read_buffer);
+            buffer.resize(MAX_DATAGRAM_SIZE, 0);
+            let result = self.receive(&mut buffer, now, max_frame_size);
+            self.read_buffer = buffer;
+            result?;
+        }
+
+        frames.extend(self.received.drain(..));
+
+        match self.state {
+            UdpState::Connecting(deadline, attempts) => {
+                if now >= deadline {
+                    self.send_control(CONNECT)?;
+                    self.state =
+                        UdpState::Connecting(now + backoff(INITIAL_RTO, attempts), attempts + 1);
+                }
+            }
+            UdpState::Connected => {
+                self.retransmit(now)?;
+                self.flush(now, outgoing)?;
+            }
+            UdpState::RemoteClosed => {
+                return Err(ProcessError::Disconnect(DisconnectReason::Closed));
+            }
+            UdpState::Closed => {}
+        }
+
+        Ok(std::mem::replace(&mut self.active, false))
+    }
+
+    /// Returns the time at which the connection has to be processed again.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            UdpState::Connecting(deadline, _) => Some(deadline),
+            UdpState::Connected => self.unacked.iter().map(|unacked| unacked.deadline).min(),
+            _ => None,
+        }
+    }
+
+    /// Returns the identifier the remote side picked for this connection in its connect request.
+    pub(crate) fn session(&self) -> u32 {
+        self.session
+    }
+
+    /// Returns true if the remote side accepted the connection.
+    pub(crate) fn established(&self) -> bool {
+        !matches!(self.state, UdpState::Connecting(..))
+    }
+
+    /// Returns true if every reliable packet sent has been acknowledged.
+    pub(crate) fn flushed(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// Starts or stops discarding received datagrams. Reliable packets are retransmitted by the remote side afterwards.
+    pub(crate) fn pause_reading(&mut self, paused: bool) {
+        self.read_paused = paused;
+    }
+
+    /// Notifies the remote side about the disconnection.
+    ///
+    /// The notification is sent only once and may get lost, in which case the remote side times out.
+    pub(crate) fn close(&mut self) {
+        if let UdpState::Connected = self.state {
+            let _ = self.send_control(DISCONNECT);
+        }
+
+        self.state = UdpState::Closed;
+    }
+
+    fn handle_reliable(&mut self, sequence: u32, payload: &[u8]) -> Result<(), Error> {
+        // Acknowledge even duplicates, the previous acknowledgement might have been lost.
+        let mut ack = header(ACK, self.session);
+        ack.extend_from_slice(&sequence.to_be_bytes());
+        self.send_datagram(&ack)?;
+
+        let offset = sequence.wrapping_sub(self.expected_reliable) as usize;
+        if offset == 0 {
+            self.received.push_back(payload.to_vec());
+            self.expected_reliable = self.expected_reliable.wrapping_add(1);
+
+            while let Some(packet) = self.out_of_order.remove(&self.expected_reliable) {
+                self.received.push_back(packet);
+                self.expected_reliable = self.expected_reliable.wrapping_add(1);
+            }
+        } else if offset < WINDOW {
+            self.out_of_order
+                .entry(sequence)
+                .or_insert_with(|| payload.to_vec());
+        }
+
+        Ok(())
+    }
+
+    fn handle_ack(&mut self, sequence: u32, now: Instant) {
+        let position = match self
+            .unacked
+            .iter()
+            .position(|unacked| unacked.sequence == sequence)
+        {
+            Some(position) => position,
+            None => return,
+        };
+
+        let unacked = self.unacked.remove(position).unwrap();
+
+        // Samples of retransmitted packets are ambiguous.
+        if unacked.retries == 0 {
+            let rtt = now - unacked.sent;
+            match self.srtt {
+                Some(srtt) => {
+                    let difference = srtt.abs_diff(rtt);
+                    self.rttvar = self.rttvar * 3 / 4 + difference / 4;
+                    self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+                }
+                None => {
+                    self.srtt = Some(rtt);
+                    self.rttvar = rtt / 2;
+                }
+            }
+        }
+    }
+
+    fn retransmit(&mut self, now: Instant) -> Result<(), Error> {
+        let rto = self.rto();
+        for unacked in self
+            .unacked
+            .iter_mut()
+            .filter(|unacked| now >= unacked.deadline)
+        {
+            unacked.retries += 1;
+            unacked.deadline = now + backoff(rto, unacked.retries);
+
+            assemble(
+                &mut self.buffer,
+                RELIABLE,
+                self.session,
+                Some(unacked.sequence),
+                &unacked.queued,
+            );
+            send_datagram(&self.socket, self.owned, &self.addr, &self.buffer)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self, now: Instant, outgoing: &mut OutgoingQueue) -> Result<(), Error> {
+        // Reliable packets have to wait while the window is full.
+        let mut window = self.unacked.len() < WINDOW;
+        while let Some(queued) =
+            outgoing.pop(|queued| window || queued.delivery != Delivery::Reliable)
+        {
+            match queued.delivery {
+                Delivery::Reliable => {
+                    let sequence = self.next_reliable;
+                    self.next_reliable = self.next_reliable.wrapping_add(1);
+
+                    assemble(
+                        &mut self.buffer,
+                        RELIABLE,
+                        self.session,
+                        Some(sequence),
+                        &queued,
+                    );
+                    self.unacked.push_back(Unacked {
+                        sequence,
+                        queued,
+                        sent: now,
+                        deadline: now + self.rto(),
+                        retries: 0,
+                    });
+                    window = self.unacked.len() < WINDOW;
+                }
+                Delivery::Sequenced => {
+                    let sequence = self.next_sequenced;
+                    self.next_sequenced = self.next_sequenced.wrapping_add(1);
+
+                    assemble(
+                        &mut self.buffer,
+                        SEQUENCED,
+                        self.session,
+                        Some(sequence),
+                        &queued,
+                    );
+                }
+                Delivery::Unreliable => {
+                    assemble(&mut self.buffer, UNRELIABLE, self.session, None, &queued);
+                }
+            }
+
+            send_datagram(&self.socket, self.owned, &self.addr, &self.buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current retransmission timeout.
+    fn rto(&self) -> Duration {
+        let rto = match self.srtt {
+            Some(srtt) => srtt + self.rttvar * 4,
+            None => INITIAL_RTO,
+        };
+
+        cmp::min(cmp::max(rto, MIN_RTO), MAX_RTO)
+    }
+
+    fn send_control(&self, kind: u8) -> Result<(), Error> {
+        self.send_datagram(&header(kind, self.session))
+    }
+
+    fn send_datagram(&self, datagram: &[u8]) -> Result<(), Error> {
+        send_datagram(&self.socket, self.owned, &self.addr, datagram)
+    }
+}
+
+/// Returns the session identifier if the datagram is a connect request.
+pub(crate) fn connect_request(datagram: &[u8]) -> Option<u32> {
+    if datagram.len() >= HEADER_SIZE && datagram[0] == CONNECT {
+        Some(session(datagram))
+    } else {
+        None
+    }
+}
+
+enum UdpState {
+    /// Connect requests are being sent until the remote side accepts. Contains the time of the next attempt
+    /// and the number of attempts so far.
+    Connecting(Instant, u32),
+    Connected,
+    /// The remote side has disconnected.
+    RemoteClosed,
+    Closed,
+}
+
+struct Unacked {
+    sequence: u32,
+    queued: Queued,
+    sent: Instant,
+    deadline: Instant,
+    retries: u32,
+}
+
+fn header(kind: u8, session: u32) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_SIZE + SEQUENCE_SIZE);
+    datagram.push(kind);
+    datagram.extend_from_slice(&session.to_be_bytes());
+    datagram
+}
+
+/// Assembles a datagram carrying a packet into `buffer`.
+fn assemble(buffer: &mut Vec<u8>, kind: u8, session: u32, sequence: Option<u32>, queued: &Queued) {
+    buffer.clear();
+    buffer.push(kind);
+    buffer.extend_from_slice(&session.to_be_bytes());
+    if let Some(sequence) = sequence {
+        buffer.extend_from_slice(&sequence.to_be_bytes());
+    }
+
+    for part in &queued.parts() {
+        buffer.extend_from_slice(part);
+    }
+}
+
+fn session(datagram: &[u8]) -> u32 {
+    u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]])
+}
+
+fn check_size(payload: &[u8], max_frame_size: usize) -> Result<(), ProcessError> {
+    if payload.len() > max_frame_size {
+        return Err(ProcessError::Disconnect(DisconnectReason::PacketTooLarge(
+            payload.len(),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Doubles the timeout for every retry.
+fn backoff(timeout: Duration, retries: u32) -> Duration {
+    cmp::min(timeout * 2u32.pow(cmp::min(retries, 6)), MAX_RTO)
+}
+
+/// Sends a datagram. Datagrams which can't be sent right away are dropped, like they would be by the network.
+fn send_datagram(
+    socket: &UdpSocket,
+    owned: bool,
+    addr: &SocketAddr,
+    datagram: &[u8],
+) -> Result<(), Error> {
+    let result = if owned {
+        socket.send(datagram)
+    } else {
+        socket.send_to(datagram, addr)
+    };
+
+    match result {
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(err) => Err(err),
+        Ok(_) => Ok(()),
+    }
+}